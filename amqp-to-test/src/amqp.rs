@@ -1,19 +1,61 @@
-use amqp_utils::{self, AmqpChannel, AmqpConnection, AmqpError, AmqpRequest, AmqpResponse};
+use amqp_utils::{
+    self, AmqpChannel, AmqpConfiguration, AmqpDelivery, AmqpError, AmqpRequest, AmqpResponse,
+    AmqpSupervisor, Envelope,
+};
 use futures::channel::mpsc::{Receiver, Sender};
-use futures::future;
 use futures::sink::SinkExt;
 use futures::stream::{StreamExt, TryStreamExt};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::mem;
+use std::error::Error;
+use std::pin::pin;
 use std::sync::Arc;
 
 use crate::config::Configuration;
+use crate::shutdown::{Phase, Shutdown};
 
+const CONSUMER_TAG: &str = "amqp-to-test";
+
+/// Jobs are identified by a number which stays unique across reconnections,
+/// and which maps to the delivery tag on the current connection. Jobs received
+/// on a previous connection will be redelivered by the broker and must not be
+/// acked on the new one.
+#[derive(Default)]
+struct Deliveries {
+    next_id: Cell<u64>,
+    tags: RefCell<HashMap<u64, u64>>,
+}
+
+impl Deliveries {
+    fn register(&self, delivery_tag: u64) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.tags.borrow_mut().insert(id, delivery_tag);
+        id
+    }
+
+    fn take(&self, id: u64) -> Option<u64> {
+        self.tags.borrow_mut().remove(&id)
+    }
+
+    fn forget_all(&self) {
+        self.tags.borrow_mut().clear();
+    }
+}
+
+/// Consume jobs until the consumer is cancelled, which is an error, or until
+/// draining starts.
 async fn amqp_receiver(
     channel: &AmqpChannel,
     config: &Arc<Configuration>,
-    send_request: Sender<AmqpRequest>,
+    send_request: Sender<Envelope<AmqpRequest>>,
+    deliveries: &Deliveries,
+    shutdown: &Shutdown,
 ) -> Result<(), AmqpError> {
+    if shutdown.phase() != Phase::Running {
+        return Ok(());
+    }
     let prefetch_count = if let Ok(p) = config.tester.parallelism.try_into() {
         p
     } else {
@@ -25,26 +67,82 @@ async fn amqp_receiver(
     };
     channel.basic_qos(prefetch_count).await?;
     let stream = channel
-        .basic_consume(&config.amqp.queue, "amqp-to-test")
-        .await?;
-    let mut data = stream
-        .map(|msg| {
-            let msg = msg?;
-            let request = msg.decode_payload()?;
-            Ok(AmqpRequest {
-                delivery_tag: Some(msg.delivery_tag()),
-                ..request
-            })
-        })
-        .filter(|e| future::ready(e.is_ok()));
-    send_request
-        .sink_map_err(|e| {
-            log::warn!("sink error: {e}");
-            AmqpError::from(e)
-        })
-        .send_all(&mut data)
+        .basic_consume(&config.amqp.queue, CONSUMER_TAG)
         .await?;
-    Ok(())
+    let mut stream = pin!(stream);
+    let mut draining = pin!(shutdown.reached(Phase::Draining));
+    let mut send_request = send_request.sink_map_err(|e| {
+        log::warn!("sink error: {e}");
+        AmqpError::from(e)
+    });
+    loop {
+        tokio::select! {
+            msg = stream.next() => {
+                let Some(msg) = msg else { break };
+                if let Some(request) = triage(channel, &config.amqp, msg?, deliveries).await? {
+                    send_request.send(request).await?;
+                }
+            }
+            () = &mut draining => {
+                log::info!("no longer consuming from queue {}", config.amqp.queue);
+                channel.basic_cancel(CONSUMER_TAG).await?;
+                return Ok(());
+            }
+        }
+    }
+    Err(AmqpError::ConsumerCancelled(config.amqp.queue.clone()))
+}
+
+/// Check that a delivery contains a valid job which has not been redelivered
+/// too many times. Other messages go to the dead-letter queue.
+async fn triage(
+    channel: &AmqpChannel,
+    config: &AmqpConfiguration,
+    msg: AmqpDelivery,
+    deliveries: &Deliveries,
+) -> Result<Option<Envelope<AmqpRequest>>, AmqpError> {
+    let mut request = match msg.decode_payload::<Envelope<AmqpRequest>>() {
+        Ok(request) => request,
+        Err(e) => {
+            let reason = match e.source() {
+                Some(source) => format!("cannot decode job: {source}"),
+                None => format!("cannot decode job: {e}"),
+            };
+            channel.dead_letter(config, &msg, &reason).await?;
+            return Ok(None);
+        }
+    };
+    if let Some(max_redeliveries) = config.max_redeliveries {
+        if msg.redelivered() {
+            if msg.redelivery_count() >= max_redeliveries {
+                let reason = format!(
+                    "job {} has been redelivered {max_redeliveries} times",
+                    request.payload.job_name
+                );
+                channel.dead_letter(config, &msg, &reason).await?;
+            } else {
+                log::info!("requeuing redelivered job {}", request.payload.job_name);
+                channel.requeue_counted(&config.queue, &msg).await?;
+            }
+            return Ok(None);
+        }
+    }
+    if request.is_legacy() {
+        log::debug!("received legacy job {}", request.payload.job_name);
+    }
+    if let Some(reply_to) = msg.reply_to() {
+        request.payload.result_queue = reply_to.to_owned();
+    }
+    if request.payload.result_queue.is_empty() {
+        let reason = format!("no result queue for job {}", request.payload.job_name);
+        channel.dead_letter(config, &msg, &reason).await?;
+        return Ok(None);
+    }
+    if request.message_id.is_none() {
+        request.message_id = msg.message_id().map(str::to_owned);
+    }
+    request.payload.delivery_tag = Some(deliveries.register(msg.delivery_tag()));
+    Ok(Some(request))
 }
 
 // Acks must be sent on the original channel. Sending concurrently
@@ -52,68 +150,72 @@ async fn amqp_receiver(
 async fn amqp_sender(
     channel: &AmqpChannel,
     ack_channel: &AmqpChannel,
-    receive_response: Receiver<AmqpResponse>,
-    reports_routing_key: Option<String>,
+    receive_response: &mut Receiver<Envelope<AmqpResponse>>,
+    reports_routing_key: Option<&str>,
+    deliveries: &Deliveries,
 ) -> Result<(), AmqpError> {
-    let channel = channel.clone();
-    let ack_channel = ack_channel.clone();
-    let reports_routing_key = reports_routing_key.clone();
     receive_response
-        .map(move |e| {
-            Ok((
-                e,
-                channel.clone(),
-                ack_channel.clone(),
-                reports_routing_key.clone(),
-            ))
-        })
-        .try_for_each(
-            move |(mut response, channel, ack_channel, reports_routing_key)| async move {
+        .map(Ok)
+        .try_for_each(|response| async move {
+            let job_name = &response.payload.job_name;
+            let queue = &response.payload.result_queue;
+            log::info!("sending response {job_name} to queue {queue}");
+            let properties = response.properties();
+            channel
+                .basic_publish_confirmed("", queue, &response, &properties)
+                .await?;
+            if let Some(delivery_tag) = deliveries.take(response.payload.delivery_tag) {
+                ack_channel.basic_ack(delivery_tag).await?;
+            } else {
                 log::info!(
-                    "sending response {} to queue {}",
-                    response.job_name,
-                    response.result_queue
+                    "job {job_name} was received before a reconnection and will be redelivered"
                 );
-                let queue = std::mem::take(&mut response.result_queue);
-                let delivery_tag = mem::replace(&mut response.delivery_tag, 0);
-                channel.basic_publish("", &queue, &response).await?;
-                ack_channel.basic_ack(delivery_tag).await?;
-                if let Some(reports_routing_key) = reports_routing_key {
-                    log::info!(
-                        "additionaly sending {} to queue {}",
-                        response.job_name,
-                        reports_routing_key
-                    );
-                    channel
-                        .basic_publish("", &reports_routing_key, &response)
-                        .await?;
-                }
-                Ok(()) as Result<(), AmqpError>
-            },
-        )
-        .await?;
-    Ok(())
+            }
+            if let Some(reports_routing_key) = reports_routing_key {
+                log::info!("additionaly sending {job_name} to queue {reports_routing_key}");
+                channel
+                    .basic_publish_confirmed("", reports_routing_key, &response, &properties)
+                    .await?;
+            }
+            Ok(())
+        })
+        .await
 }
 
 #[allow(clippy::module_name_repetitions)]
 pub async fn amqp_process(
     config: &Arc<Configuration>,
-    send_request: Sender<AmqpRequest>,
-    receive_response: Receiver<AmqpResponse>,
+    shutdown: &Shutdown,
+    send_request: Sender<Envelope<AmqpRequest>>,
+    mut receive_response: Receiver<Envelope<AmqpResponse>>,
 ) -> Result<(), AmqpError> {
-    let conn = AmqpConnection::new(&config.amqp).await?;
-    let receiver_channel = conn.create_channel().await?;
-    receiver_channel
-        .declare_exchange_and_queue(&config.amqp)
-        .await?;
-    let receiver = amqp_receiver(&receiver_channel, config, send_request);
-    let sender_channel = conn.create_channel().await?;
-    let sender = amqp_sender(
-        &sender_channel,
-        &receiver_channel,
-        receive_response,
-        config.amqp.reports_routing_key.clone(),
-    );
-    futures::try_join!(receiver, sender)?;
-    Ok(())
+    let deliveries = Deliveries::default();
+    AmqpSupervisor::new(&config.amqp)
+        .run(async |conn| {
+            deliveries.forget_all();
+            let receiver_channel = conn.create_channel().await?;
+            receiver_channel
+                .declare_exchange_and_queue(&config.amqp)
+                .await?;
+            receiver_channel.confirm_select().await?;
+            let receiver = amqp_receiver(
+                &receiver_channel,
+                config,
+                send_request.clone(),
+                &deliveries,
+                shutdown,
+            );
+            let sender_channel = conn.create_channel().await?;
+            sender_channel.confirm_select().await?;
+            let sender = amqp_sender(
+                &sender_channel,
+                &receiver_channel,
+                &mut receive_response,
+                config.amqp.reports_routing_key.as_deref(),
+                &deliveries,
+            );
+            futures::try_join!(receiver, sender)?;
+            Ok(())
+        })
+        .await
 }
@@ -11,6 +11,8 @@ pub enum ConfigurationError {
     CannotDecode(#[source] serde_yaml::Error, String),
     #[error("cannot read configuration file {1}")]
     CannotRead(#[source] std::io::Error, String),
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
 }
 
 #[derive(Deserialize)]
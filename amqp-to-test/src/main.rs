@@ -1,5 +1,8 @@
 mod amqp;
 mod config;
+mod runner;
+mod sandbox;
+mod shutdown;
 mod tester;
 
 use amqp_utils::AmqpError;
@@ -8,8 +11,12 @@ use config::{Configuration, ConfigurationError};
 use futures::FutureExt;
 use futures::channel::mpsc;
 use futures::try_join;
+use shutdown::{Phase, Shutdown};
 use std::sync::Arc;
 
+/// Name used to identify the messages produced by this program
+static PRODUCER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("configuration error")]
@@ -31,10 +38,27 @@ async fn main() -> Result<(), eyre::Report> {
     color_eyre::install()?;
     log::info!("starting");
     let config = Arc::new(configuration()?);
+    let runner = config.tester.runner()?;
     let (send_request, receive_request) = mpsc::channel(16);
     let (send_response, receive_response) = mpsc::channel(16);
-    let executor = tester::start_executor(&config, receive_request, send_response).map(Ok);
-    let amqp_process = amqp::amqp_process(&config, send_request, receive_response);
+    let shutdown = Shutdown::install(config.tester.drain_timeout())?;
+    let executor = tester::start_executor(
+        &config,
+        runner,
+        shutdown.clone(),
+        receive_request,
+        send_response,
+    )
+    .map(Ok);
+    let amqp_process = amqp::amqp_process(&config, &shutdown, send_request, receive_response);
+    // Responses which could not be published when stopping will be redelivered.
+    let amqp_process = async {
+        tokio::select! {
+            result = amqp_process => result,
+            () = shutdown.reached(Phase::Stopping) => Ok(()),
+        }
+    };
     try_join!(executor, amqp_process)?;
+    log::info!("exiting");
     Ok(())
 }
@@ -0,0 +1,158 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunnerKind {
+    #[default]
+    Docker,
+    Podman,
+    /// Run the builder directly as a subprocess, without container
+    Local,
+}
+
+/// Everything needed to run the builder on a job
+pub struct Job {
+    /// Unique name, also used as the container name
+    pub name: String,
+    pub env: BTreeMap<String, String>,
+    /// Container restrictions, ignored when running locally
+    pub sandbox: Vec<String>,
+    /// Options given to the builder before its positional arguments
+    pub builder_args: Vec<String>,
+    pub zip_url: String,
+    pub dir: String,
+    /// Test program, relative to the labs directory
+    pub program: PathBuf,
+    /// Test file, relative to the labs directory
+    pub test_file: PathBuf,
+}
+
+impl Job {
+    /// Builder arguments when the labs directory is available as `labs_dir`
+    fn args(&self, labs_dir: &Path) -> Vec<OsString> {
+        let mut args: Vec<OsString> = self.builder_args.iter().map(OsString::from).collect();
+        args.push(OsString::from(&self.zip_url));
+        args.push(OsString::from(&self.dir));
+        args.push(labs_dir.join(&self.program).into_os_string());
+        args.push(labs_dir.join(&self.test_file).into_os_string());
+        args
+    }
+}
+
+pub trait Runner: Send + Sync {
+    /// Command running the builder for `job`
+    fn command(&self, job: &Job) -> Command;
+
+    /// Command to use to kill `job`, whose main process is `pid`
+    fn kill_command(&self, job: &Job, pid: Option<u32>) -> Option<Command>;
+}
+
+/// Run the builder in a docker or podman container
+pub struct ContainerRunner {
+    pub binary: String,
+    pub image: String,
+    pub dir_on_host: PathBuf,
+    pub dir_in_container: PathBuf,
+}
+
+impl Runner for ContainerRunner {
+    fn command(&self, job: &Job) -> Command {
+        let mut command = Command::new(&self.binary);
+        command
+            .arg("run")
+            .arg("--rm")
+            .arg("--name")
+            .arg(&job.name)
+            .arg("-v")
+            .arg(format!(
+                "{}:{}",
+                self.dir_on_host.to_str().unwrap(),
+                self.dir_in_container.to_str().unwrap()
+            ))
+            .args(
+                job.env
+                    .iter()
+                    .flat_map(|(k, v)| ["-e".to_owned(), format!("{k}={v}")]),
+            )
+            .args(&job.sandbox)
+            .arg(&self.image)
+            .args(job.args(&self.dir_in_container));
+        command
+    }
+
+    fn kill_command(&self, job: &Job, _pid: Option<u32>) -> Option<Command> {
+        let mut command = Command::new(&self.binary);
+        command.arg("kill").arg(&job.name);
+        Some(command)
+    }
+}
+
+/// Run the builder as a local subprocess, in its own process group
+pub struct LocalRunner {
+    pub builder: PathBuf,
+    pub labs_dir: PathBuf,
+}
+
+impl Runner for LocalRunner {
+    fn command(&self, job: &Job) -> Command {
+        let mut command = Command::new(&self.builder);
+        command
+            .envs(&job.env)
+            .args(job.args(&self.labs_dir))
+            .process_group(0);
+        command
+    }
+
+    fn kill_command(&self, _job: &Job, pid: Option<u32>) -> Option<Command> {
+        let mut command = Command::new("kill");
+        command.arg("-KILL").arg("--").arg(format!("-{}", pid?));
+        Some(command)
+    }
+}
+
+#[test]
+fn test_container_command() {
+    let runner = ContainerRunner {
+        binary: "podman".to_owned(),
+        image: "rfc1149/builder".to_owned(),
+        dir_on_host: PathBuf::from("/srv/labs"),
+        dir_in_container: PathBuf::from("/labs"),
+    };
+    let job = Job {
+        name: "job-1".to_owned(),
+        env: BTreeMap::from([("RUNTIME_LIB".to_owned(), "/labs/lib.a".to_owned())]),
+        sandbox: vec!["--pids-limit=256".to_owned()],
+        builder_args: vec!["--with-llvm=/usr/lib/llvm".to_owned()],
+        zip_url: "http://localhost/zips/job.zip".to_owned(),
+        dir: "dragon-tiger".to_owned(),
+        program: PathBuf::from("test.py"),
+        test_file: PathBuf::from("lab3/lab3.yml"),
+    };
+    let command = runner.command(&job);
+    let command = command.as_std();
+    assert_eq!(command.get_program(), "podman");
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        vec![
+            "run",
+            "--rm",
+            "--name",
+            "job-1",
+            "-v",
+            "/srv/labs:/labs",
+            "-e",
+            "RUNTIME_LIB=/labs/lib.a",
+            "--pids-limit=256",
+            "rfc1149/builder",
+            "--with-llvm=/usr/lib/llvm",
+            "http://localhost/zips/job.zip",
+            "dragon-tiger",
+            "/labs/test.py",
+            "/labs/lab3/lab3.yml"
+        ]
+    );
+}
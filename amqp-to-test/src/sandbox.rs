@@ -0,0 +1,108 @@
+use serde::Deserialize;
+
+/// Restrictions applied to the container running the tests. Unset fields
+/// keep the container runtime defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SandboxConfiguration {
+    /// Memory limit, such as `2g`
+    pub memory: Option<String>,
+    /// Number of CPUs, such as `1.5`
+    pub cpus: Option<f64>,
+    /// Maximum number of processes, to stop fork bombs
+    pub pids_limit: Option<u32>,
+    /// Network to connect the container to, such as `none`. Note that the
+    /// builder needs to access the zip file URL.
+    pub network: Option<String>,
+    /// Mount the container root filesystem as read-only
+    pub read_only: Option<bool>,
+    /// Writable temporary filesystems, such as `/tmp`
+    pub tmpfs: Option<Vec<String>>,
+    /// User (and optionally group) to run as, such as `1000:1000`
+    pub user: Option<String>,
+    /// Prevent processes from gaining additional privileges
+    pub no_new_privileges: Option<bool>,
+    /// Drop all capabilities
+    pub drop_capabilities: Option<bool>,
+}
+
+impl SandboxConfiguration {
+    /// Return a configuration where fields set in `other` take precedence.
+    #[must_use]
+    pub fn overridden_by(&self, other: &SandboxConfiguration) -> SandboxConfiguration {
+        SandboxConfiguration {
+            memory: other.memory.clone().or_else(|| self.memory.clone()),
+            cpus: other.cpus.or(self.cpus),
+            pids_limit: other.pids_limit.or(self.pids_limit),
+            network: other.network.clone().or_else(|| self.network.clone()),
+            read_only: other.read_only.or(self.read_only),
+            tmpfs: other.tmpfs.clone().or_else(|| self.tmpfs.clone()),
+            user: other.user.clone().or_else(|| self.user.clone()),
+            no_new_privileges: other.no_new_privileges.or(self.no_new_privileges),
+            drop_capabilities: other.drop_capabilities.or(self.drop_capabilities),
+        }
+    }
+
+    /// Translate the configuration into docker command line arguments.
+    #[must_use]
+    pub fn docker_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(ref memory) = self.memory {
+            args.extend([
+                format!("--memory={memory}"),
+                format!("--memory-swap={memory}"),
+            ]);
+        }
+        if let Some(cpus) = self.cpus {
+            args.push(format!("--cpus={cpus}"));
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            args.push(format!("--pids-limit={pids_limit}"));
+        }
+        if let Some(ref network) = self.network {
+            args.push(format!("--network={network}"));
+        }
+        if self.read_only == Some(true) {
+            args.push("--read-only".to_owned());
+        }
+        for tmpfs in self.tmpfs.iter().flatten() {
+            args.push(format!("--tmpfs={tmpfs}"));
+        }
+        if let Some(ref user) = self.user {
+            args.push(format!("--user={user}"));
+        }
+        if self.no_new_privileges == Some(true) {
+            args.push("--security-opt=no-new-privileges".to_owned());
+        }
+        if self.drop_capabilities == Some(true) {
+            args.push("--cap-drop=ALL".to_owned());
+        }
+        args
+    }
+}
+
+#[test]
+fn test_docker_args() {
+    let default = SandboxConfiguration {
+        memory: Some("1g".to_owned()),
+        pids_limit: Some(256),
+        read_only: Some(true),
+        tmpfs: Some(vec!["/tmp".to_owned()]),
+        ..SandboxConfiguration::default()
+    };
+    let lab = SandboxConfiguration {
+        memory: Some("4g".to_owned()),
+        cpus: Some(1.5),
+        read_only: Some(false),
+        ..SandboxConfiguration::default()
+    };
+    assert_eq!(
+        default.overridden_by(&lab).docker_args(),
+        vec![
+            "--memory=4g",
+            "--memory-swap=4g",
+            "--cpus=1.5",
+            "--pids-limit=256",
+            "--tmpfs=/tmp"
+        ]
+    );
+}
@@ -0,0 +1,52 @@
+use std::io;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    /// No new job is accepted, running jobs are allowed to finish
+    Draining,
+    /// Running jobs must be killed
+    Stopping,
+}
+
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<Phase>);
+
+impl Shutdown {
+    /// Install the signal handlers. The first SIGTERM or SIGINT starts draining,
+    /// the second one or the expiration of `deadline` forces the stop.
+    pub fn install(deadline: Duration) -> io::Result<Shutdown> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let (sender, receiver) = watch::channel(Phase::Running);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sigterm.recv() => (),
+                _ = sigint.recv() => (),
+            }
+            log::warn!("draining running jobs for at most {deadline:?}, signal again to stop now");
+            sender.send_replace(Phase::Draining);
+            tokio::select! {
+                _ = sigterm.recv() => log::warn!("stopping now"),
+                _ = sigint.recv() => log::warn!("stopping now"),
+                () = tokio::time::sleep(deadline) => log::warn!("drain deadline expired"),
+            }
+            sender.send_replace(Phase::Stopping);
+        });
+        Ok(Shutdown(receiver))
+    }
+
+    pub fn phase(&self) -> Phase {
+        *self.0.borrow()
+    }
+
+    /// Wait until the given phase has been reached.
+    pub async fn reached(&self, phase: Phase) {
+        let mut receiver = self.0.clone();
+        // An error means that the signal handler is gone, which never happens.
+        let _ = receiver.wait_for(|current| *current >= phase).await;
+    }
+}
@@ -1,14 +1,23 @@
-use amqp_utils::{AmqpRequest, AmqpResponse};
+use amqp_utils::{AmqpRequest, AmqpResponse, Envelope};
 use futures::channel::mpsc::{Receiver, Sender};
 use futures::{SinkExt, StreamExt, TryFutureExt};
+use graders_utils::errorkind::ErrorKind;
+use graders_utils::logs::{LOGS_KEY, PhaseLog};
 use serde::{Deserialize, Serialize};
 use std::collections::btree_map::BTreeMap;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::pin::pin;
+use std::process::Stdio;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
-use crate::config;
+use crate::config::{self, ConfigurationError};
+use crate::runner::{ContainerRunner, Job, LocalRunner, Runner, RunnerKind};
+use crate::sandbox::SandboxConfiguration;
+use crate::shutdown::{Phase, Shutdown};
 
 #[derive(Debug, thiserror::Error)]
 pub enum TesterError {
@@ -18,12 +27,33 @@ pub enum TesterError {
     ConfigurationNotFound(String, String),
     #[error("execution error: {0}")]
     ExecutionError(String),
+    #[error("timed out after {0} seconds")]
+    Timeout(u64),
+    #[error("interrupted by shutdown")]
+    Interrupted,
+}
+
+impl TesterError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TesterError::Timeout(_) => ErrorKind::Timeout,
+            TesterError::CannotRun(_)
+            | TesterError::ConfigurationNotFound(..)
+            | TesterError::ExecutionError(_)
+            | TesterError::Interrupted => ErrorKind::InfrastructureError,
+        }
+    }
 }
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Deserialize)]
 pub struct TesterConfiguration {
-    pub docker_image: String,
+    #[serde(default)]
+    pub runner: RunnerKind,
+    /// Image used by the docker and podman runners
+    pub docker_image: Option<String>,
+    /// Builder executable used by the local runner
+    pub builder: Option<PathBuf>,
     pub dir_on_host: PathBuf,
     pub dir_in_docker: PathBuf,
     pub env: Option<BTreeMap<String, BTreeMap<String, String>>>,
@@ -31,104 +61,266 @@ pub struct TesterConfiguration {
     pub parallelism: usize,
     pub program: PathBuf,
     pub test_files: BTreeMap<String, PathBuf>,
+    /// Default wall-clock timeout in seconds for a job
+    pub timeout: Option<u64>,
+    /// Per-lab wall-clock timeout in seconds, overriding the default one
+    pub timeouts: Option<BTreeMap<String, u64>>,
+    /// Default container restrictions
+    pub sandbox: Option<SandboxConfiguration>,
+    /// Per-lab container restrictions, overriding the default ones
+    pub sandboxes: Option<BTreeMap<String, SandboxConfiguration>>,
+    /// Per-lab build instructions, when the build system should not be detected
+    pub builds: Option<BTreeMap<String, BuildConfiguration>>,
+    /// Time in seconds given to running jobs to finish after a first
+    /// termination signal, before they get killed
+    pub drain_timeout: Option<u64>,
+}
+
+/// How the builder must build and test the program, passed on its command line
+#[derive(Debug, Default, Deserialize)]
+pub struct BuildConfiguration {
+    /// One of `autotools`, `cmake`, `meson` or `cargo`
+    pub system: Option<String>,
+    /// Shell commands replacing the build system ones
+    pub commands: Option<Vec<String>>,
+    /// Compiler path relative to the source directory
+    pub executable: Option<String>,
+    /// Let the builder run the test file itself instead of the test program
+    pub native_tests: Option<bool>,
+}
+
+impl BuildConfiguration {
+    fn builder_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(ref system) = self.system {
+            args.push(format!("--build-system={system}"));
+        }
+        for command in self.commands.iter().flatten() {
+            args.push(format!("--build-command={command}"));
+        }
+        if let Some(ref executable) = self.executable {
+            args.push(format!("--executable={executable}"));
+        }
+        if self.native_tests == Some(true) {
+            args.push("--native-tests".to_owned());
+        }
+        args
+    }
+}
+
+impl TesterConfiguration {
+    pub fn runner(&self) -> Result<Arc<dyn Runner>, ConfigurationError> {
+        let container_runner = |binary: &str| {
+            let image = self.docker_image.clone().ok_or_else(|| {
+                ConfigurationError::Invalid(format!("docker_image is required by {binary}"))
+            })?;
+            Ok(Arc::new(ContainerRunner {
+                binary: binary.to_owned(),
+                image,
+                dir_on_host: self.dir_on_host.clone(),
+                dir_in_container: self.dir_in_docker.clone(),
+            }) as Arc<dyn Runner>)
+        };
+        match self.runner {
+            RunnerKind::Docker => container_runner("docker"),
+            RunnerKind::Podman => container_runner("podman"),
+            RunnerKind::Local => {
+                let builder = self.builder.clone().ok_or_else(|| {
+                    ConfigurationError::Invalid("builder is required by the local runner".into())
+                })?;
+                Ok(Arc::new(LocalRunner {
+                    builder,
+                    labs_dir: self.dir_on_host.clone(),
+                }))
+            }
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT))
+    }
+
+    fn timeout(&self, lab: &str) -> Option<u64> {
+        self.timeouts
+            .as_ref()
+            .and_then(|timeouts| timeouts.get(lab))
+            .copied()
+            .or(self.timeout)
+    }
+
+    /// Options given to the builder for `lab`
+    fn builder_args(&self, lab: &str) -> Vec<String> {
+        let mut args = self.extra_args.clone().unwrap_or_default();
+        if let Some(build) = self.builds.as_ref().and_then(|builds| builds.get(lab)) {
+            args.extend(build.builder_args());
+        }
+        args
+    }
+
+    fn sandbox(&self, lab: &str) -> SandboxConfiguration {
+        let default = self.sandbox.clone().unwrap_or_default();
+        match self
+            .sandboxes
+            .as_ref()
+            .and_then(|sandboxes| sandboxes.get(lab))
+        {
+            Some(sandbox) => default.overridden_by(sandbox),
+            None => default,
+        }
+    }
 }
 
-/// Execute a request using docker on a blocking CPU pool. Return the
-/// YAML output or a descriptive error.
+/// Default value in seconds of `drain_timeout`
+const DEFAULT_DRAIN_TIMEOUT: u64 = 600;
+
+/// Return a job name unique to this instance of the program
+fn job_name() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "amqp-to-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+async fn kill(runner: &dyn Runner, job: &Job, pid: Option<u32>) {
+    let Some(mut command) = runner.kill_command(job, pid) else {
+        return;
+    };
+    log::info!("killing job {}", job.name);
+    match command.stdin(Stdio::null()).output().await {
+        Ok(output) if output.status.success() => (),
+        Ok(output) => log::warn!(
+            "cannot kill job {}: {}",
+            job.name,
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => log::warn!("cannot kill job {}: {e}", job.name),
+    }
+}
+
+/// Execute a request using the configured runner. Return the YAML output or
+/// a descriptive error. The job is killed if it runs longer than the lab timeout,
+/// or if the program is stopping.
 async fn execute(
     config: &TesterConfiguration,
+    runner: &dyn Runner,
     request: &AmqpRequest,
     cpu_access: Arc<Semaphore>,
+    shutdown: &Shutdown,
 ) -> Result<String, TesterError> {
-    let test_file = match config.test_files.get(&request.lab) {
-        Some(file) => config.dir_in_docker.join(file),
-        None => {
-            return Err(TesterError::ConfigurationNotFound(
-                request.lab.clone(),
-                request.job_name.clone(),
-            ));
-        }
+    let Some(test_file) = config.test_files.get(&request.lab) else {
+        return Err(TesterError::ConfigurationNotFound(
+            request.lab.clone(),
+            request.job_name.clone(),
+        ));
+    };
+    let job = Job {
+        name: job_name(),
+        env: config
+            .env
+            .as_ref()
+            .and_then(|env| env.get(&request.lab))
+            .cloned()
+            .unwrap_or_default(),
+        sandbox: config.sandbox(&request.lab).docker_args(),
+        builder_args: config.builder_args(&request.lab),
+        zip_url: request.zip_url.clone(),
+        dir: request.dir.clone(),
+        program: config.program.clone(),
+        test_file: test_file.clone(),
     };
-    let request = request.clone();
-    let program = config.dir_in_docker.join(&config.program);
-    let dir_on_host = config.dir_on_host.clone();
-    let dir_in_docker = config.dir_in_docker.clone();
-    let env = config
-        .env
-        .clone()
-        .unwrap_or_default()
-        .get(&request.lab)
-        .cloned()
-        .unwrap_or_default()
-        .iter()
-        .flat_map(|(k, v)| vec!["-e".to_owned(), format!("{k}={v}")])
-        .collect::<Vec<_>>();
-    let docker_image = config.docker_image.clone();
-    let extra_args = config.extra_args.clone().unwrap_or_default();
+    let mut command = runner.command(&job);
     let _permit = cpu_access.acquire().await;
-    tokio::task::spawn_blocking(move || {
-        log::info!("starting docker command for {}", request.job_name);
-        let mut command = Command::new("docker");
-        let command = command
-            .arg("run")
-            .arg("--rm")
-            .arg("-v")
-            .arg(format!(
-                "{}:{}",
-                dir_on_host.to_str().unwrap(),
-                dir_in_docker.to_str().unwrap()
-            ))
-            .args(env)
-            .arg(&docker_image)
-            .args(extra_args)
-            .arg(&request.zip_url)
-            .arg(&request.dir)
-            .arg(&program)
-            .arg(&test_file);
-        log::trace!("docker command for {}: {:?}", request.job_name, command);
-        let output = command
-            .stdin(Stdio::null())
-            .output()
-            .map_err(TesterError::CannotRun)?;
-        if output.status.code() == Some(0) {
-            log::info!(
-                "docker command for {} finished succesfully",
-                request.job_name
-            );
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
-        } else {
+    log::info!("starting job {} for {}", job.name, request.job_name);
+    log::trace!("command for {}: {:?}", request.job_name, command);
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(TesterError::CannotRun)?;
+    let pid = child.id();
+    let output = child.wait_with_output();
+    let timeout = config.timeout(&request.lab);
+    let expired = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(Duration::from_secs(timeout)).await,
+            None => std::future::pending().await,
+        }
+    };
+    let output = tokio::select! {
+        output = output => output.map_err(TesterError::CannotRun)?,
+        () = expired => {
             log::warn!(
-                "docker command for {} finished with an error",
-                request.job_name
+                "job {} for {} timed out after {} seconds",
+                job.name,
+                request.job_name,
+                timeout.unwrap()
             );
-            Err(TesterError::ExecutionError(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            ))
+            kill(runner, &job, pid).await;
+            return Err(TesterError::Timeout(timeout.unwrap()));
         }
-    })
-    .await
-    .unwrap()
+        () = shutdown.reached(Phase::Stopping) => {
+            log::warn!("job {} for {} interrupted", job.name, request.job_name);
+            kill(runner, &job, pid).await;
+            return Err(TesterError::Interrupted);
+        }
+    };
+    if output.status.code() == Some(0) {
+        log::info!("job {} finished succesfully", request.job_name);
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        log::warn!("job {} finished with an error", request.job_name);
+        Err(TesterError::ExecutionError(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
 }
 
-/// Execute a request using docker and build a response containing the
-/// YAML output or response.
+/// Execute a request and build a response containing the YAML output
+/// or response. No response is built for an interrupted job, which must
+/// be redelivered.
 async fn execute_request(
     config: &TesterConfiguration,
-    request: AmqpRequest,
+    runner: &dyn Runner,
+    envelope: Envelope<AmqpRequest>,
     cpu_access: Arc<Semaphore>,
-) -> AmqpResponse {
-    let yaml = match execute(config, &request, cpu_access).await {
-        Ok(y) => y,
-        Err(e) => yaml_error(&e),
+    shutdown: &Shutdown,
+) -> Option<Envelope<AmqpResponse>> {
+    let request = &envelope.payload;
+    let (yaml, logs) = match execute(config, runner, request, cpu_access, shutdown).await {
+        Ok(y) => split_logs(y),
+        Err(TesterError::Interrupted) => return None,
+        Err(e) => (yaml_error(&e), None),
     };
-    AmqpResponse {
-        job_name: request.job_name,
-        lab: request.lab,
-        opaque: request.opaque,
-        yaml_result: yaml,
-        result_queue: request.result_queue,
-        delivery_tag: request.delivery_tag.unwrap(),
-    }
+    Some(envelope.reply(
+        crate::PRODUCER,
+        AmqpResponse {
+            job_name: request.job_name.clone(),
+            lab: request.lab.clone(),
+            opaque: request.opaque.clone(),
+            yaml_result: yaml,
+            logs,
+            result_queue: request.result_queue.clone(),
+            delivery_tag: request.delivery_tag.unwrap(),
+        },
+    ))
+}
+
+/// Move the logs added by the builder out of the YAML result
+fn split_logs(yaml: String) -> (String, Option<Vec<PhaseLog>>) {
+    let Ok(serde_yaml::Value::Mapping(mut mapping)) = serde_yaml::from_str(&yaml) else {
+        return (yaml, None);
+    };
+    let Some(logs) = mapping.remove(LOGS_KEY) else {
+        return (yaml, None);
+    };
+    let logs = serde_yaml::from_value(logs)
+        .inspect_err(|e| log::warn!("cannot decode builder logs: {e}"))
+        .ok();
+    (serde_yaml::to_string(&mapping).unwrap(), logs)
 }
 
 #[derive(Serialize)]
@@ -137,6 +329,8 @@ struct ExecutionErrorReport {
     #[serde(rename = "max-grade")]
     max_grade: usize,
     explanation: String,
+    #[serde(rename = "error-kind")]
+    error_kind: ErrorKind,
 }
 
 fn yaml_error(error: &TesterError) -> String {
@@ -144,35 +338,74 @@ fn yaml_error(error: &TesterError) -> String {
         grade: 0,
         max_grade: 1,
         explanation: error.to_string(),
+        error_kind: error.kind(),
     })
     .unwrap()
 }
 
-/// Start the executors on the current thread
+/// Start the executors on the current thread. New requests are no longer
+/// accepted once draining starts, and this function returns when all
+/// running jobs are over.
 pub async fn start_executor(
     config: &Arc<config::Configuration>,
-    receive_request: Receiver<AmqpRequest>,
-    send_response: Sender<AmqpResponse>,
+    runner: Arc<dyn Runner>,
+    shutdown: Shutdown,
+    receive_request: Receiver<Envelope<AmqpRequest>>,
+    send_response: Sender<Envelope<AmqpResponse>>,
 ) {
     let cpu_access = Arc::new(Semaphore::new(config.tester.parallelism));
-    receive_request
-        .for_each(move |request| {
-            let cpu_access = cpu_access.clone();
-            let send_response = send_response.clone();
-            async move {
+    let mut requests = pin!(receive_request.take_until(shutdown.reached(Phase::Draining)));
+    let mut jobs = JoinSet::new();
+    loop {
+        tokio::select! {
+            request = requests.next() => {
+                let Some(request) = request else { break };
                 log::debug!("received request {request:?}");
+                let cpu_access = cpu_access.clone();
                 let mut send_response = send_response.clone();
+                let runner = runner.clone();
                 let config = config.clone();
-                tokio::spawn(async move {
-                    let response = execute_request(&config.tester, request, cpu_access).await;
-                    send_response
+                let shutdown = shutdown.clone();
+                jobs.spawn(async move {
+                    let Some(response) = execute_request(
+                        &config.tester,
+                        runner.as_ref(),
+                        request,
+                        cpu_access,
+                        &shutdown,
+                    )
+                    .await
+                    else {
+                        return;
+                    };
+                    let _ = send_response
                         .send(response)
                         .inspect_err(|e| {
                             log::error!("unable to send AMQPResponse to queue: {e}");
                         })
-                        .await
+                        .await;
                 });
             }
-        })
-        .await;
+            Some(_) = jobs.join_next() => (),
+        }
+    }
+    if !jobs.is_empty() {
+        log::info!("waiting for {} running jobs to finish", jobs.len());
+    }
+    while jobs.join_next().await.is_some() {}
+}
+
+#[test]
+fn test_split_logs() {
+    let yaml = "grade: 0\nmax-grade: 1\nexplanation: build failed\nlogs:\n- phase: make\n  command: make\n  status: 2\n  stdout: ''\n  stderr: 'main.c: error'\n";
+    let (yaml, logs) = split_logs(yaml.to_owned());
+    assert_eq!(yaml, "grade: 0\nmax-grade: 1\nexplanation: build failed\n");
+    let logs = logs.unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].phase, "make");
+    assert!(!logs[0].success());
+    assert!(!logs[0].truncated);
+    let (yaml, logs) = split_logs("grade: 1\nmax-grade: 1\n".to_owned());
+    assert_eq!(yaml, "grade: 1\nmax-grade: 1\n");
+    assert!(logs.is_none());
 }
@@ -1,17 +1,28 @@
 #![allow(clippy::module_name_repetitions)]
 
-use crate::{AmqpConfiguration, AmqpDelivery, AmqpError};
+use crate::{AmqpConfiguration, AmqpDelivery, AmqpError, MessageProperties};
 use futures::future::TryFutureExt;
 use futures::stream::{Stream, TryStreamExt};
 use lapin::options::{
-    BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+    BasicPublishOptions, BasicQosOptions, BasicRejectOptions, ConfirmSelectOptions,
     ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
 };
-use lapin::types::FieldTable;
+use lapin::publisher_confirm::{Confirmation, PublisherConfirm};
+use lapin::types::{AMQPValue, FieldTable};
 use lapin::{BasicProperties, Channel, ExchangeKind};
 use serde::ser::Serialize;
 use std::rc::Rc;
 
+/// Delivery mode of persistent messages
+const PERSISTENT: u8 = 2;
+
+/// Header holding the number of times a message has been redelivered
+pub const REDELIVERY_COUNT_HEADER: &str = "x-redelivery-count";
+
+/// Header holding the reason why a message has been dead-lettered
+pub const ERROR_HEADER: &str = "x-grader-error";
+
 #[derive(Clone)]
 pub struct AmqpChannel {
     pub(crate) inner: Rc<Channel>,
@@ -59,6 +70,53 @@ impl AmqpChannel {
                 );
             })
             .await?;
+        if let Some(ref dead_letter_exchange) = config.dead_letter_exchange {
+            self.declare_dead_letter(dead_letter_exchange, config)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Declare the dead-letter exchange and bind the dead-letter queue to it.
+    async fn declare_dead_letter(
+        &self,
+        dead_letter_exchange: &str,
+        config: &AmqpConfiguration,
+    ) -> Result<(), AmqpError> {
+        let dead_letter_queue = config.dead_letter_queue();
+        self.inner
+            .exchange_declare(
+                dead_letter_exchange,
+                ExchangeKind::Direct,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .inspect_err(|e| {
+                log::error!("cannot declare dead-letter exchange {dead_letter_exchange}: {e}");
+            })
+            .await?;
+        self.queue_declare_durable(&dead_letter_queue)
+            .inspect_err(|e| {
+                log::error!("could not declare dead-letter queue {dead_letter_queue}: {e}");
+            })
+            .await?;
+        self.inner
+            .queue_bind(
+                &dead_letter_queue,
+                dead_letter_exchange,
+                &config.routing_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .inspect_err(|e| {
+                log::error!(
+                    "could not bind dead-letter queue {dead_letter_queue} to exchange {dead_letter_exchange}: {e}"
+                );
+            })
+            .await?;
         Ok(())
     }
 
@@ -73,18 +131,144 @@ impl AmqpChannel {
         exchange: &str,
         routing_key: &str,
         data: &T,
+        properties: &MessageProperties,
     ) -> Result<(), AmqpError>
     where
         T: ?Sized + Serialize,
     {
-        self.inner
+        self.publish(
+            exchange,
+            routing_key,
+            data,
+            properties.to_basic_properties(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Publish data as a persistent message and wait for the broker to
+    /// confirm that it has taken responsibility for it. The channel must have
+    /// been put in confirm mode using [`AmqpChannel::confirm_select`].
+    ///
+    /// # Panics
+    ///
+    /// Serialization can fail if `T`'s implementation of `Serialize` decides to
+    /// fail, or if `T` contains a map with non-string keys.
+    pub async fn basic_publish_confirmed<T>(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &T,
+        properties: &MessageProperties,
+    ) -> Result<(), AmqpError>
+    where
+        T: ?Sized + Serialize,
+    {
+        let properties = properties
+            .to_basic_properties()
+            .with_delivery_mode(PERSISTENT);
+        let confirm = self
+            .publish(exchange, routing_key, data, properties)
+            .await?;
+        check_confirmation(exchange, routing_key, confirm).await
+    }
+
+    async fn publish<T>(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &T,
+        properties: BasicProperties,
+    ) -> Result<PublisherConfirm, AmqpError>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.publish_bytes(
+            exchange,
+            routing_key,
+            serde_json::to_string(data).unwrap().as_bytes(),
+            properties,
+        )
+        .await
+    }
+
+    async fn publish_bytes(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<PublisherConfirm, AmqpError> {
+        Ok(self
+            .inner
             .basic_publish(
                 exchange,
                 routing_key,
                 BasicPublishOptions::default(),
-                serde_json::to_string(data).unwrap().as_bytes(),
-                BasicProperties::default(),
+                payload,
+                properties,
+            )
+            .await?)
+    }
+
+    /// Move a delivery received on this channel to the dead-letter exchange
+    /// with `reason` attached, and ack it. If no dead-letter exchange is
+    /// configured, the delivery is rejected without being requeued.
+    /// The channel must be in confirm mode.
+    pub async fn dead_letter(
+        &self,
+        config: &AmqpConfiguration,
+        delivery: &AmqpDelivery,
+        reason: &str,
+    ) -> Result<(), AmqpError> {
+        let Some(ref dead_letter_exchange) = config.dead_letter_exchange else {
+            log::warn!("no dead-letter exchange configured, dropping message: {reason}");
+            return self.basic_reject(delivery.delivery_tag(), false).await;
+        };
+        log::warn!(
+            "sending message to dead-letter queue {}: {reason}",
+            config.dead_letter_queue()
+        );
+        let properties = delivery
+            .with_header(ERROR_HEADER, AMQPValue::LongString(reason.into()))
+            .with_delivery_mode(PERSISTENT);
+        let confirm = self
+            .publish_bytes(
+                dead_letter_exchange,
+                &config.routing_key,
+                &delivery.inner.data,
+                properties,
+            )
+            .await?;
+        check_confirmation(dead_letter_exchange, &config.routing_key, confirm).await?;
+        self.basic_ack(delivery.delivery_tag()).await
+    }
+
+    /// Publish a redelivered message again at the end of `queue` with its
+    /// redelivery counter incremented, and ack the original delivery.
+    /// The channel must be in confirm mode.
+    pub async fn requeue_counted(
+        &self,
+        queue: &str,
+        delivery: &AmqpDelivery,
+    ) -> Result<(), AmqpError> {
+        let properties = delivery
+            .with_header(
+                REDELIVERY_COUNT_HEADER,
+                AMQPValue::LongUInt(delivery.redelivery_count() + 1),
             )
+            .with_delivery_mode(PERSISTENT);
+        let confirm = self
+            .publish_bytes("", queue, &delivery.inner.data, properties)
+            .await?;
+        check_confirmation("", queue, confirm).await?;
+        self.basic_ack(delivery.delivery_tag()).await
+    }
+
+    /// Ask the broker to confirm every message published on this channel.
+    pub async fn confirm_select(&self) -> Result<(), AmqpError> {
+        self.inner
+            .confirm_select(ConfirmSelectOptions::default())
             .await?;
         Ok(())
     }
@@ -96,6 +280,26 @@ impl AmqpChannel {
         Ok(())
     }
 
+    pub async fn basic_nack(&self, delivery_tag: u64, requeue: bool) -> Result<(), AmqpError> {
+        self.inner
+            .basic_nack(
+                delivery_tag,
+                BasicNackOptions {
+                    multiple: false,
+                    requeue,
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn basic_reject(&self, delivery_tag: u64, requeue: bool) -> Result<(), AmqpError> {
+        self.inner
+            .basic_reject(delivery_tag, BasicRejectOptions { requeue })
+            .await?;
+        Ok(())
+    }
+
     pub async fn basic_qos(&self, prefetch_count: u16) -> Result<(), AmqpError> {
         self.inner
             .basic_qos(prefetch_count, BasicQosOptions { global: false })
@@ -135,4 +339,28 @@ impl AmqpChannel {
             .map_ok(|delivery| AmqpDelivery { inner: delivery })
             .map_err(AmqpError::from))
     }
+
+    /// Stop the consumer identified by `consumer_tag`. Deliveries already
+    /// received stay unacked until they are acked or the channel is closed.
+    pub async fn basic_cancel(&self, consumer_tag: &str) -> Result<(), AmqpError> {
+        self.inner
+            .basic_cancel(consumer_tag, BasicCancelOptions::default())
+            .await?;
+        Ok(())
+    }
+}
+
+async fn check_confirmation(
+    exchange: &str,
+    routing_key: &str,
+    confirm: PublisherConfirm,
+) -> Result<(), AmqpError> {
+    match confirm.await? {
+        Confirmation::Ack(_) => Ok(()),
+        Confirmation::Nack(_) => {
+            log::error!("message to {routing_key} on exchange {exchange:?} refused by broker");
+            Err(AmqpError::Nacked(routing_key.to_owned()))
+        }
+        Confirmation::NotRequested => Err(AmqpError::ConfirmNotSelected),
+    }
 }
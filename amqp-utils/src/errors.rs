@@ -1,4 +1,5 @@
 use futures::channel::mpsc::SendError;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -7,6 +8,16 @@ pub enum AmqpError {
     Json(#[from] serde_json::error::Error),
     #[error("AMQP error")]
     Lapin(#[from] lapin::Error),
+    #[error("cannot read {1:?}")]
+    CannotRead(#[source] std::io::Error, PathBuf),
+    #[error("consumer on queue {0} has been cancelled")]
+    ConsumerCancelled(String),
+    #[error("client certificate and key must be given together")]
+    IncompleteClientCertificate,
+    #[error("publisher confirms have not been enabled on the channel")]
+    ConfirmNotSelected,
+    #[error("message to {0} has been refused by the broker")]
+    Nacked(String),
     #[error("Sink error")]
     SinkError(#[from] SendError),
     #[error("UTF-8 decoding error")]
@@ -2,7 +2,9 @@
 
 mod channel;
 mod errors;
+mod supervisor;
 mod types;
 pub use channel::*;
 pub use errors::*;
+pub use supervisor::*;
 pub use types::*;
@@ -0,0 +1,130 @@
+use crate::{AmqpConfiguration, AmqpConnection, AmqpError};
+use futures::future::{self, Either};
+use futures::stream::StreamExt;
+use graders_utils::time::backoff_delay;
+use serde::Deserialize;
+use std::error::Error;
+use std::pin::pin;
+use std::time::Duration;
+
+/// Reconnection policy used by [`AmqpSupervisor`]. Delays are expressed
+/// in seconds and doubled after every failed attempt.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfiguration {
+    pub initial_delay: u64,
+    pub max_delay: u64,
+    /// Give up after this many consecutive failed attempts (retry forever if unset)
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfiguration {
+    fn default() -> Self {
+        ReconnectConfiguration {
+            initial_delay: 1,
+            max_delay: 60,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectConfiguration {
+    /// Delay to wait before the given reconnection attempt (starting at 1).
+    #[must_use]
+    pub fn delay(&self, attempt: u32) -> Duration {
+        backoff_delay(self.initial_delay, self.max_delay, attempt)
+    }
+}
+
+/// Keep an AMQP connection alive by reconnecting with exponential backoff
+/// whenever the broker goes away.
+pub struct AmqpSupervisor {
+    config: AmqpConfiguration,
+}
+
+impl AmqpSupervisor {
+    #[must_use]
+    pub fn new(config: &AmqpConfiguration) -> AmqpSupervisor {
+        AmqpSupervisor {
+            config: config.clone(),
+        }
+    }
+
+    /// Run `session` on a fresh connection every time the previous one is lost.
+    /// The session is in charge of creating its channels, declaring exchanges and
+    /// queues and starting its consumers. It should return `Ok(())` when its work
+    /// is over, and an error when the connection or one of its channels failed.
+    pub async fn run<F>(self, mut session: F) -> Result<(), AmqpError>
+    where
+        F: AsyncFnMut(&AmqpConnection) -> Result<(), AmqpError>,
+    {
+        let reconnect = self.config.reconnect.clone();
+        let mut attempt = 0;
+        loop {
+            let error = match AmqpConnection::new(&self.config).await {
+                Ok(conn) => {
+                    attempt = 0;
+                    log::info!("connected to AMQP server {}", self.config.description());
+                    match self.supervise(&conn, &mut session).await {
+                        Ok(()) => return Ok(()),
+                        Err(e) => e,
+                    }
+                }
+                Err(e) => e,
+            };
+            let reason = describe(&error);
+            log::warn!("AMQP connection lost: {reason}");
+            attempt += 1;
+            if reconnect.max_attempts.is_some_and(|max| attempt > max) {
+                log::error!("giving up reconnecting to AMQP server after {attempt} attempts");
+                return Err(error);
+            }
+            let delay = reconnect.delay(attempt);
+            log::info!("reconnecting to AMQP server in {delay:?} (attempt {attempt})");
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Run the session while logging broker flow-control events.
+    async fn supervise<F>(&self, conn: &AmqpConnection, session: &mut F) -> Result<(), AmqpError>
+    where
+        F: AsyncFnMut(&AmqpConnection) -> Result<(), AmqpError>,
+    {
+        let mut events = pin!(conn.inner.events_listener());
+        let mut session = pin!(session(conn));
+        loop {
+            match future::select(session.as_mut(), events.next()).await {
+                Either::Left((result, _)) => return result,
+                Either::Right((Some(lapin::Event::ConnectionBlocked(reason)), _)) => {
+                    log::warn!("AMQP connection blocked by the server: {reason}");
+                }
+                Either::Right((Some(lapin::Event::ConnectionUnblocked), _)) => {
+                    log::info!("AMQP connection unblocked by the server");
+                }
+                Either::Right((Some(_), _)) => (),
+                Either::Right((None, _)) => return session.await,
+            }
+        }
+    }
+}
+
+fn describe(error: &AmqpError) -> String {
+    match error.source() {
+        Some(source) => format!("{error}: {source}"),
+        None => error.to_string(),
+    }
+}
+
+#[test]
+fn test_reconnect_delay() {
+    let config = ReconnectConfiguration {
+        initial_delay: 2,
+        max_delay: 30,
+        max_attempts: None,
+    };
+    assert_eq!(config.delay(1), Duration::from_secs(2));
+    assert_eq!(config.delay(2), Duration::from_secs(4));
+    assert_eq!(config.delay(4), Duration::from_secs(16));
+    assert_eq!(config.delay(5), Duration::from_secs(30));
+    assert_eq!(config.delay(100), Duration::from_secs(30));
+}
@@ -1,9 +1,22 @@
-use crate::AmqpChannel;
 use crate::errors::AmqpError;
+use crate::{AmqpChannel, REDELIVERY_COUNT_HEADER, ReconnectConfiguration};
 use futures::future::TryFutureExt;
-use lapin::{Connection, ConnectionProperties};
+use graders_utils::logs::PhaseLog;
+use graders_utils::time::unix_time;
+use lapin::tcp::{OwnedIdentity, OwnedTLSConfig};
+use lapin::types::{AMQPValue, FieldTable, ShortString};
+use lapin::uri::{AMQPAuthority, AMQPQueryString, AMQPScheme, AMQPUri, AMQPUserInfo};
+use lapin::{BasicProperties, Connection, ConnectionProperties};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use uuid::Uuid;
+
+/// Version of the message schema produced by this crate. Messages without
+/// schema version are decoded as version 0.
+pub const SCHEMA_VERSION: u32 = 1;
 
 pub struct AmqpConnection {
     pub(crate) inner: Connection,
@@ -12,13 +25,16 @@ pub struct AmqpConnection {
 impl AmqpConnection {
     /// Return a client that will connect to a remote AMQP server.
     pub async fn new(config: &AmqpConfiguration) -> Result<AmqpConnection, AmqpError> {
-        // TODO Check how to add heartbeat
-        let dest = format!("amqp://{}:{}/%2f", config.host, config.port);
-        let connection = Connection::connect(&dest, ConnectionProperties::default())
-            .inspect_err(|e| {
-                log::warn!("error when connecting AMQP client to {dest}: {e}");
-            })
-            .await?;
+        let dest = config.description();
+        let connection = Connection::connect_uri_with_config(
+            config.uri()?,
+            ConnectionProperties::default(),
+            config.tls_config()?,
+        )
+        .inspect_err(|e| {
+            log::warn!("error when connecting AMQP client to {dest}: {e}");
+        })
+        .await?;
         Ok(AmqpConnection { inner: connection })
     }
 
@@ -39,6 +55,86 @@ impl AmqpDelivery {
     pub fn delivery_tag(&self) -> u64 {
         self.inner.delivery_tag
     }
+
+    /// Check whether the broker already tried to deliver this message
+    #[must_use]
+    pub fn redelivered(&self) -> bool {
+        self.inner.redelivered
+    }
+
+    /// Number of times this message has been requeued after a redelivery
+    #[must_use]
+    pub fn redelivery_count(&self) -> u32 {
+        self.inner
+            .properties
+            .headers()
+            .as_ref()
+            .and_then(|headers| headers.inner().get(REDELIVERY_COUNT_HEADER))
+            .and_then(AMQPValue::as_long_uint)
+            .unwrap_or(0)
+    }
+
+    #[must_use]
+    pub fn message_id(&self) -> Option<&str> {
+        self.inner
+            .properties
+            .message_id()
+            .as_ref()
+            .map(ShortString::as_str)
+    }
+
+    #[must_use]
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.inner
+            .properties
+            .correlation_id()
+            .as_ref()
+            .map(ShortString::as_str)
+    }
+
+    /// Queue to which answers to this message must be sent
+    #[must_use]
+    pub fn reply_to(&self) -> Option<&str> {
+        self.inner
+            .properties
+            .reply_to()
+            .as_ref()
+            .map(ShortString::as_str)
+    }
+
+    #[must_use]
+    pub fn content_type(&self) -> Option<&str> {
+        self.inner
+            .properties
+            .content_type()
+            .as_ref()
+            .map(ShortString::as_str)
+    }
+
+    /// Value of a string header
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<String> {
+        match self
+            .inner
+            .properties
+            .headers()
+            .as_ref()?
+            .inner()
+            .get(name)?
+        {
+            AMQPValue::LongString(s) => Some(s.to_string()),
+            AMQPValue::ShortString(s) => Some(s.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Properties of this message with an additional header
+    pub(crate) fn with_header(&self, name: &str, value: AMQPValue) -> BasicProperties {
+        let mut headers = self.inner.properties.headers().clone().unwrap_or_default();
+        headers.insert(name.into(), value);
+        self.inner.properties.clone().with_headers(headers)
+    }
+
     pub fn decode_payload<'de, T: Deserialize<'de>>(&'de self) -> Result<T, AmqpError> {
         let s = std::str::from_utf8(&self.inner.data)?;
         Ok(serde_json::from_str::<T>(s)?)
@@ -49,10 +145,237 @@ impl AmqpDelivery {
 pub struct AmqpConfiguration {
     pub host: String,
     pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// File containing the password, used when `password` is not set
+    pub password_file: Option<PathBuf>,
+    /// Virtual host, `/` if unset
+    pub vhost: Option<String>,
+    /// Heartbeat interval in seconds, the server proposal is used if unset
+    pub heartbeat: Option<u16>,
+    /// Connect using `amqps` if set
+    pub tls: Option<AmqpTlsConfiguration>,
     pub exchange: String,
     pub routing_key: String,
     pub queue: String,
     pub reports_routing_key: Option<String>,
+    #[serde(default)]
+    pub reconnect: ReconnectConfiguration,
+    /// Exchange receiving the messages which cannot be processed, bound to
+    /// queue `<queue>.dead`
+    pub dead_letter_exchange: Option<String>,
+    /// Number of redeliveries after which a job goes to the dead-letter queue
+    pub max_redeliveries: Option<u32>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AmqpTlsConfiguration {
+    /// PEM bundle containing the certificate authorities to trust
+    pub ca_file: Option<PathBuf>,
+    /// PEM-encoded client certificate
+    pub cert_file: Option<PathBuf>,
+    /// PEM-encoded PKCS#8 key of the client certificate
+    pub key_file: Option<PathBuf>,
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, AmqpError> {
+    fs::read(path).map_err(|e| AmqpError::CannotRead(e, path.to_owned()))
+}
+
+impl AmqpConfiguration {
+    fn credentials(&self) -> Result<AMQPUserInfo, AmqpError> {
+        let default = AMQPUserInfo::default();
+        let password = match (&self.password, &self.password_file) {
+            (Some(password), _) => password.clone(),
+            (None, Some(file)) => String::from_utf8_lossy(&read_file(file)?)
+                .trim_end()
+                .to_owned(),
+            (None, None) => default.password,
+        };
+        Ok(AMQPUserInfo {
+            username: self.username.clone().unwrap_or(default.username),
+            password,
+        })
+    }
+
+    fn uri(&self) -> Result<AMQPUri, AmqpError> {
+        Ok(AMQPUri {
+            scheme: if self.tls.is_some() {
+                AMQPScheme::AMQPS
+            } else {
+                AMQPScheme::AMQP
+            },
+            authority: AMQPAuthority {
+                userinfo: self.credentials()?,
+                host: self.host.clone(),
+                port: self.port,
+            },
+            vhost: self.vhost.clone().unwrap_or_else(|| "/".to_owned()),
+            query: AMQPQueryString {
+                heartbeat: self.heartbeat,
+                ..AMQPQueryString::default()
+            },
+        })
+    }
+
+    fn tls_config(&self) -> Result<OwnedTLSConfig, AmqpError> {
+        let Some(tls) = &self.tls else {
+            return Ok(OwnedTLSConfig::default());
+        };
+        let cert_chain = match &tls.ca_file {
+            Some(file) => Some(String::from_utf8_lossy(&read_file(file)?).into_owned()),
+            None => None,
+        };
+        let identity = match (&tls.cert_file, &tls.key_file) {
+            (Some(cert), Some(key)) => Some(OwnedIdentity::PKCS8 {
+                pem: read_file(cert)?,
+                key: read_file(key)?,
+            }),
+            (None, None) => None,
+            _ => return Err(AmqpError::IncompleteClientCertificate),
+        };
+        Ok(OwnedTLSConfig {
+            identity,
+            cert_chain,
+        })
+    }
+
+    #[must_use]
+    pub fn dead_letter_queue(&self) -> String {
+        format!("{}.dead", self.queue)
+    }
+
+    /// Description of the server suitable for logging (without the password)
+    #[must_use]
+    pub fn description(&self) -> String {
+        format!(
+            "{}://{}@{}:{}/{}",
+            if self.tls.is_some() { "amqps" } else { "amqp" },
+            self.username.as_deref().unwrap_or("guest"),
+            self.host,
+            self.port,
+            self.vhost.as_deref().unwrap_or("/")
+        )
+    }
+}
+
+/// Standard AMQP properties attached to a published message. The content
+/// type is always set to `application/json`.
+#[derive(Clone, Debug, Default)]
+pub struct MessageProperties {
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+    /// Queue to which answers to this message must be sent
+    pub reply_to: Option<String>,
+    /// Creation time in seconds since the Unix epoch
+    pub timestamp: Option<u64>,
+    pub app_id: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl MessageProperties {
+    #[must_use]
+    pub fn with_reply_to(mut self, queue: &str) -> MessageProperties {
+        self.reply_to = Some(queue.to_owned());
+        self
+    }
+
+    #[must_use]
+    pub fn with_header(mut self, name: &str, value: &str) -> MessageProperties {
+        self.headers.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    pub(crate) fn to_basic_properties(&self) -> BasicProperties {
+        let mut properties =
+            BasicProperties::default().with_content_type("application/json".into());
+        if let Some(ref message_id) = self.message_id {
+            properties = properties.with_message_id(message_id.as_str().into());
+        }
+        if let Some(ref correlation_id) = self.correlation_id {
+            properties = properties.with_correlation_id(correlation_id.as_str().into());
+        }
+        if let Some(ref reply_to) = self.reply_to {
+            properties = properties.with_reply_to(reply_to.as_str().into());
+        }
+        if let Some(timestamp) = self.timestamp {
+            properties = properties.with_timestamp(timestamp);
+        }
+        if let Some(ref app_id) = self.app_id {
+            properties = properties.with_app_id(app_id.as_str().into());
+        }
+        if !self.headers.is_empty() {
+            let mut headers = FieldTable::default();
+            for (name, value) in &self.headers {
+                headers.insert(
+                    name.as_str().into(),
+                    AMQPValue::LongString(value.as_str().into()),
+                );
+            }
+            properties = properties.with_headers(headers);
+        }
+        properties
+    }
+}
+
+/// Metadata surrounding a message. It is flattened next to the payload fields
+/// so that consumers unaware of the envelope can still decode the payload, and
+/// so that payloads sent without envelope can still be decoded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// Identifier of the message this one is an answer to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Creation time in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer: Option<String>,
+    #[serde(flatten)]
+    pub payload: T,
+}
+
+impl<T> Envelope<T> {
+    /// Wrap a new message with a fresh identifier.
+    pub fn new(producer: &str, payload: T) -> Envelope<T> {
+        Envelope {
+            schema_version: SCHEMA_VERSION,
+            message_id: Some(Uuid::new_v4().to_string()),
+            correlation_id: None,
+            timestamp: Some(unix_time()),
+            producer: Some(producer.to_owned()),
+            payload,
+        }
+    }
+
+    /// Wrap an answer to this message.
+    pub fn reply<U>(&self, producer: &str, payload: U) -> Envelope<U> {
+        Envelope {
+            correlation_id: self.message_id.clone(),
+            ..Envelope::new(producer, payload)
+        }
+    }
+
+    /// AMQP properties mirroring the envelope metadata
+    #[must_use]
+    pub fn properties(&self) -> MessageProperties {
+        MessageProperties {
+            message_id: self.message_id.clone(),
+            correlation_id: self.correlation_id.clone(),
+            timestamp: self.timestamp,
+            app_id: self.producer.clone(),
+            ..MessageProperties::default()
+        }
+    }
+
+    #[must_use]
+    pub fn is_legacy(&self) -> bool {
+        self.schema_version == 0
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -61,9 +384,12 @@ pub struct AmqpRequest {
     pub lab: String,
     pub dir: String,
     pub zip_url: String,
+    /// Deprecated, the `reply_to` property of the message should be used instead
+    #[serde(default)]
     pub result_queue: String,
     pub opaque: String,
     /// The delivery tag will be set upon message reception
+    #[serde(skip)]
     pub delivery_tag: Option<u64>,
 }
 
@@ -73,7 +399,180 @@ pub struct AmqpResponse {
     pub lab: String,
     pub opaque: String,
     pub yaml_result: String,
-    /// The delivery tag and result queue will be removed before message emission
+    /// Logs of the build and test phases, if the builder provided them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logs: Option<Vec<PhaseLog>>,
+    /// The delivery tag and result queue are only used locally. They are
+    /// still sent in schema version 1, with a dummy delivery tag, because
+    /// consumers predating the envelope require them.
+    #[serde(default)]
     pub result_queue: String,
+    #[serde(default, serialize_with = "serialize_dummy_delivery_tag")]
     pub delivery_tag: u64,
 }
+
+fn serialize_dummy_delivery_tag<S: serde::Serializer>(
+    _: &u64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(0)
+}
+
+#[test]
+fn test_amqp_uri() {
+    let config: AmqpConfiguration = serde_json::from_str(
+        r#"{"host": "broker", "port": 5671, "exchange": "grader", "routing_key": "lab",
+            "queue": "jobs", "username": "course", "password": "secret",
+            "vhost": "compilers", "heartbeat": 30, "tls": {}}"#,
+    )
+    .unwrap();
+    let uri = config.uri().unwrap();
+    assert_eq!(uri.scheme, AMQPScheme::AMQPS);
+    assert_eq!(uri.authority.userinfo.username, "course");
+    assert_eq!(uri.authority.userinfo.password, "secret");
+    assert_eq!(uri.vhost, "compilers");
+    assert_eq!(uri.query.heartbeat, Some(30));
+    assert_eq!(config.description(), "amqps://course@broker:5671/compilers");
+}
+
+#[test]
+fn test_legacy_request() {
+    let request: Envelope<AmqpRequest> = serde_json::from_str(
+        r#"{"job_name": "xqueue:42", "lab": "lab3", "dir": "dragon-tiger",
+            "zip_url": "http://xqueue/submission.zip", "result_queue": "xqueue_grader",
+            "opaque": "{}", "delivery_tag": null}"#,
+    )
+    .unwrap();
+    assert!(request.is_legacy());
+    assert_eq!(request.message_id, None);
+    assert_eq!(request.payload.job_name, "xqueue:42");
+    assert_eq!(request.payload.delivery_tag, None);
+}
+
+#[test]
+fn test_envelope_round_trip() {
+    let request = Envelope::new(
+        "gitlab-to-amqp",
+        AmqpRequest {
+            job_name: "job".to_owned(),
+            lab: "lab3".to_owned(),
+            dir: "dragon-tiger".to_owned(),
+            zip_url: "http://localhost/zips/job.zip".to_owned(),
+            result_queue: "gitlab".to_owned(),
+            opaque: String::new(),
+            delivery_tag: Some(3),
+        },
+    );
+    let encoded = serde_json::to_string(&request).unwrap();
+    assert!(!encoded.contains("delivery_tag"));
+    let decoded: Envelope<AmqpRequest> = serde_json::from_str(&encoded).unwrap();
+    assert_eq!(decoded.schema_version, SCHEMA_VERSION);
+    assert_eq!(decoded.message_id, request.message_id);
+    assert_eq!(decoded.producer.as_deref(), Some("gitlab-to-amqp"));
+    let response = decoded.reply(
+        "amqp-to-test",
+        AmqpResponse {
+            job_name: decoded.payload.job_name.clone(),
+            lab: decoded.payload.lab.clone(),
+            opaque: decoded.payload.opaque.clone(),
+            yaml_result: "grade: 1\nmax-grade: 1\n".to_owned(),
+            logs: None,
+            result_queue: decoded.payload.result_queue.clone(),
+            delivery_tag: 3,
+        },
+    );
+    assert_eq!(response.correlation_id, request.message_id);
+    // Consumers unaware of the envelope still see the bare response
+    let bare: AmqpResponse =
+        serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+    assert_eq!(bare.job_name, "job");
+}
+
+#[test]
+fn test_baseline_consumers() {
+    /// Messages as decoded before the introduction of the envelope
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct BaselineAmqpRequest {
+        job_name: String,
+        lab: String,
+        dir: String,
+        zip_url: String,
+        result_queue: String,
+        opaque: String,
+        delivery_tag: Option<u64>,
+    }
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct BaselineAmqpResponse {
+        job_name: String,
+        lab: String,
+        opaque: String,
+        yaml_result: String,
+        result_queue: String,
+        delivery_tag: u64,
+    }
+
+    let request = Envelope::new(
+        "gitlab-to-amqp",
+        AmqpRequest {
+            job_name: "job".to_owned(),
+            lab: "lab3".to_owned(),
+            dir: "dragon-tiger".to_owned(),
+            zip_url: "http://localhost/zips/job.zip".to_owned(),
+            result_queue: "gitlab".to_owned(),
+            opaque: String::new(),
+            delivery_tag: Some(3),
+        },
+    );
+    let baseline: BaselineAmqpRequest =
+        serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+    assert_eq!(baseline.result_queue, "gitlab");
+    assert_eq!(baseline.delivery_tag, None);
+    let response = request.reply(
+        "amqp-to-test",
+        AmqpResponse {
+            job_name: "job".to_owned(),
+            lab: "lab3".to_owned(),
+            opaque: String::new(),
+            yaml_result: "grade: 1\nmax-grade: 1\n".to_owned(),
+            logs: None,
+            result_queue: "gitlab".to_owned(),
+            delivery_tag: 3,
+        },
+    );
+    let baseline: BaselineAmqpResponse =
+        serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+    assert_eq!(baseline.job_name, "job");
+    assert_eq!(baseline.result_queue, "gitlab");
+    assert_eq!(baseline.delivery_tag, 0);
+}
+
+#[test]
+fn test_message_properties() {
+    let properties = MessageProperties {
+        message_id: Some("id".to_owned()),
+        correlation_id: Some("request-id".to_owned()),
+        ..MessageProperties::default()
+    }
+    .with_reply_to("gitlab")
+    .with_header("x-grader-lab", "lab3")
+    .to_basic_properties();
+    assert_eq!(
+        properties.content_type().as_ref().unwrap().as_str(),
+        "application/json"
+    );
+    assert_eq!(properties.message_id().as_ref().unwrap().as_str(), "id");
+    assert_eq!(
+        properties.correlation_id().as_ref().unwrap().as_str(),
+        "request-id"
+    );
+    assert_eq!(properties.reply_to().as_ref().unwrap().as_str(), "gitlab");
+    assert!(
+        properties
+            .headers()
+            .as_ref()
+            .unwrap()
+            .contains_key("x-grader-lab")
+    );
+}
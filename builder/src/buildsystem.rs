@@ -0,0 +1,55 @@
+use clap::ValueEnum;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BuildSystem {
+    /// `make`, falling back to `./configure` then to `./autogen.sh`
+    Autotools,
+    Cmake,
+    Meson,
+    Cargo,
+}
+
+impl BuildSystem {
+    /// Detect the build system from the files present in `src`. Autotools
+    /// take precedence, and are used if nothing else is recognized.
+    pub fn detect(src: &Path) -> BuildSystem {
+        let has = |file: &str| src.join(file).exists();
+        if ["Makefile", "configure", "configure.ac", "autogen.sh"]
+            .into_iter()
+            .any(has)
+        {
+            BuildSystem::Autotools
+        } else if has("Cargo.toml") {
+            BuildSystem::Cargo
+        } else if has("meson.build") {
+            BuildSystem::Meson
+        } else if has("CMakeLists.txt") {
+            BuildSystem::Cmake
+        } else {
+            BuildSystem::Autotools
+        }
+    }
+
+    /// Location of the compiler relative to the source directory, unless
+    /// told otherwise
+    pub fn default_executable(self) -> &'static str {
+        match self {
+            BuildSystem::Autotools => "src/driver/dtiger",
+            BuildSystem::Cmake | BuildSystem::Meson => "build/dtiger",
+            BuildSystem::Cargo => "target/release/dtiger",
+        }
+    }
+}
+
+#[test]
+fn test_detect() {
+    let dir = tempfile::TempDir::new().unwrap();
+    assert_eq!(BuildSystem::detect(dir.path()), BuildSystem::Autotools);
+    std::fs::write(dir.path().join("CMakeLists.txt"), "").unwrap();
+    assert_eq!(BuildSystem::detect(dir.path()), BuildSystem::Cmake);
+    std::fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    assert_eq!(BuildSystem::detect(dir.path()), BuildSystem::Cargo);
+    std::fs::write(dir.path().join("configure.ac"), "").unwrap();
+    assert_eq!(BuildSystem::detect(dir.path()), BuildSystem::Autotools);
+}
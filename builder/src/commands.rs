@@ -1,4 +1,7 @@
 use super::Opt;
+use crate::buildsystem::BuildSystem;
+use graders_utils::errorkind::ErrorKind;
+use graders_utils::logs::PhaseLog;
 use is_executable::IsExecutable;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
@@ -12,10 +15,33 @@ pub enum RunError {
     #[error("some files (e.g, `{0}`) should be executable, but the executable bit is not set")]
     NotExecutable(PathBuf),
     #[error("{0}")]
-    Other(String),
+    BuildFailed(String),
+    #[error("the build did not produce the expected executable `{0}`")]
+    MissingExecutable(PathBuf),
+    #[error("{0}")]
+    TestsFailed(String),
+    #[error("cannot read test file `{1}`: {0}")]
+    CannotReadTestFile(String, PathBuf),
+    #[error("cannot run test `{1}`")]
+    CannotRunTest(#[source] std::io::Error, String),
+}
+
+impl RunError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RunError::CannotBuildProgram(..)
+            | RunError::BuildFailed(_)
+            | RunError::MissingExecutable(_) => ErrorKind::BuildError,
+            RunError::NotExecutable(_) => ErrorKind::NotExecutable,
+            RunError::CannotRunTests(..)
+            | RunError::TestsFailed(_)
+            | RunError::CannotReadTestFile(..)
+            | RunError::CannotRunTest(..) => ErrorKind::HarnessError,
+        }
+    }
 }
 
-pub fn run_test(opt: &Opt, dtiger: &Path) -> Result<String, RunError> {
+pub fn run_test(opt: &Opt, dtiger: &Path, logs: &mut Vec<PhaseLog>) -> Result<String, RunError> {
     log::info!(
         "executing {:?} with test source {:?} on executable {:?}",
         opt.test_command,
@@ -36,57 +62,86 @@ pub fn run_test(opt: &Opt, dtiger: &Path) -> Result<String, RunError> {
         .map_err(|e| {
             RunError::CannotRunTests(e, opt.test_command.clone(), opt.test_file.clone())
         })?;
-    if output.status.code() == Some(0) {
+    // On success, the standard output is the YAML result itself
+    let success = output.status.code() == Some(0);
+    logs.push(PhaseLog::new(
+        "tests",
+        opt.test_command.to_string_lossy().into_owned(),
+        output.status.code(),
+        if success { b"" } else { &output.stdout },
+        &output.stderr,
+        opt.max_log_size,
+    ));
+    if success {
         Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     } else {
         log::warn!(
             "received status code {:?} when running tests",
             output.status.code()
         );
-        Err(RunError::Other(
+        Err(RunError::TestsFailed(
             String::from_utf8_lossy(&output.stderr).into_owned(),
         ))
     }
 }
 
-fn exec(opt: &Opt, command: &str) -> Result<(), RunError> {
-    exec_args(opt, command, &[])
+fn exec(opt: &Opt, logs: &mut Vec<PhaseLog>, phase: &str, command: &str) -> Result<(), RunError> {
+    exec_args(opt, logs, phase, command, &[])
 }
 
-fn exec_args(opt: &Opt, command: &str, args: &[&str]) -> Result<(), RunError> {
+/// Execute a build command and record its outputs in `logs`
+fn exec_args(
+    opt: &Opt,
+    logs: &mut Vec<PhaseLog>,
+    phase: &str,
+    command: &str,
+    args: &[&str],
+) -> Result<(), RunError> {
     log::info!("executing {command} with args {args:?}");
     let output = Command::new(command)
         .args(args)
         .current_dir(&opt.src)
         .stdin(Stdio::null())
-        .stdout(Stdio::null())
         .output()
         .map_err(|e| RunError::CannotBuildProgram(e, opt.src.clone()))?;
     log::trace!("command {command} with args {args:?} terminated with status {output:?}");
+    logs.push(PhaseLog::new(
+        phase,
+        std::iter::once(command)
+            .chain(args.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" "),
+        output.status.code(),
+        &output.stdout,
+        &output.stderr,
+        opt.max_log_size,
+    ));
     if output.status.code() == Some(0) {
         Ok(())
     } else {
-        Err(RunError::Other(
+        Err(RunError::BuildFailed(
             String::from_utf8_lossy(&output.stderr).to_string(),
         ))
     }
 }
 
-fn make(opt: &Opt) -> Result<(), RunError> {
-    exec(opt, "make")
+fn make(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
+    exec(opt, logs, "make", "make")
 }
 
-fn configure(opt: &Opt) -> Result<(), RunError> {
+fn configure(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
     let configure = if let Some(ref d) = opt.with_llvm {
         exec_args(
             opt,
+            logs,
+            "configure",
             "./configure",
             &[&format!("--with-llvm={}", d.to_str().unwrap())],
         )
     } else {
-        exec(opt, "./configure")
+        exec(opt, logs, "configure", "./configure")
     };
-    configure.and_then(|_| make(opt))
+    configure.and_then(|()| make(opt, logs))
 }
 
 fn check_executable_bits(opt: &Opt) -> Result<(), RunError> {
@@ -99,13 +154,70 @@ fn check_executable_bits(opt: &Opt) -> Result<(), RunError> {
     Ok(())
 }
 
-fn autogen(opt: &Opt) -> Result<(), RunError> {
-    exec(opt, "./autogen.sh").and_then(|_| configure(opt))
+fn autogen(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
+    exec(opt, logs, "autogen", "./autogen.sh").and_then(|()| configure(opt, logs))
+}
+
+fn autotools(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
+    make(opt, logs)
+        .or_else(|_| configure(opt, logs))
+        .or_else(|_| autogen(opt, logs))
+}
+
+fn cmake(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
+    let prefix_path = opt
+        .with_llvm
+        .as_ref()
+        .map(|d| format!("-DCMAKE_PREFIX_PATH={}", d.to_str().unwrap()));
+    let mut args = vec!["-S", ".", "-B", "build"];
+    args.extend(prefix_path.as_deref());
+    exec_args(opt, logs, "configure", "cmake", &args)?;
+    exec_args(opt, logs, "make", "cmake", &["--build", "build"])
+}
+
+fn meson(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
+    exec_args(opt, logs, "configure", "meson", &["setup", "build"])?;
+    exec_args(opt, logs, "make", "meson", &["compile", "-C", "build"])
 }
 
-pub fn build(opt: &Opt) -> Result<(), RunError> {
+fn cargo(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
+    exec_args(opt, logs, "make", "cargo", &["build", "--release"])
+}
+
+/// Run the user-supplied build commands through the shell, in order
+fn custom(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<(), RunError> {
+    for command in &opt.build_command {
+        exec_args(opt, logs, "build", "sh", &["-c", command])?;
+    }
+    Ok(())
+}
+
+/// Build the program, recording the outputs of every command in `logs`, and
+/// return the path of the compiler executable.
+pub fn build(opt: &Opt, logs: &mut Vec<PhaseLog>) -> Result<PathBuf, RunError> {
     check_executable_bits(opt)?;
-    make(opt)
-        .or_else(|_| configure(opt))
-        .or_else(|_| autogen(opt))
+    let build_system = opt
+        .build_system
+        .unwrap_or_else(|| BuildSystem::detect(Path::new(&opt.src)));
+    log::info!("using build system {build_system:?}");
+    if opt.build_command.is_empty() {
+        match build_system {
+            BuildSystem::Autotools => autotools(opt, logs),
+            BuildSystem::Cmake => cmake(opt, logs),
+            BuildSystem::Meson => meson(opt, logs),
+            BuildSystem::Cargo => cargo(opt, logs),
+        }?;
+    } else {
+        custom(opt, logs)?;
+    }
+    let executable = Path::new(&opt.src).join(
+        opt.executable
+            .as_deref()
+            .unwrap_or_else(|| Path::new(build_system.default_executable())),
+    );
+    if executable.is_file() {
+        Ok(executable)
+    } else {
+        Err(RunError::MissingExecutable(executable))
+    }
 }
@@ -0,0 +1,306 @@
+use graders_utils::visibility::Visibility;
+use serde_derive::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::commands::RunError;
+
+/// Per-test timeout in seconds when the test file does not set one
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Signal used to kill tests which time out
+const SIGKILL: u32 = 9;
+
+/// Maximum size in bytes of the outputs and diffs reported for a failing test
+const MAX_EXCERPT_SIZE: usize = 2048;
+
+/// Lab test file, as written by the teachers
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TestFile {
+    /// Default timeout in seconds for every test
+    timeout: Option<u64>,
+    groups: Vec<GroupSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct GroupSpec {
+    description: Option<String>,
+    #[serde(default)]
+    visibility: Visibility,
+    tests: Vec<TestSpec>,
+}
+
+/// A test runs the compiler with `args`, followed by the source file if any.
+/// Without expectations, the test succeeds if the exit status is 0.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TestSpec {
+    description: String,
+    #[serde(default = "default_coefficient")]
+    coefficient: usize,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Source file, relative to the test file
+    file: Option<PathBuf>,
+    /// Inline source, used if `file` is not set
+    source: Option<String>,
+    stdin: Option<String>,
+    expected_stdout: Option<String>,
+    #[serde(default)]
+    expected_status: i32,
+    /// Text which must appear in the standard error
+    expected_stderr: Option<String>,
+    timeout: Option<u64>,
+    visibility: Option<Visibility>,
+}
+
+fn default_coefficient() -> usize {
+    1
+}
+
+/// Test results, in the format produced by the external test programs
+#[derive(Serialize)]
+struct Report {
+    grade: usize,
+    #[serde(rename = "max-grade")]
+    max_grade: usize,
+    groups: Vec<GroupReport>,
+}
+
+#[derive(Serialize)]
+struct GroupReport {
+    grade: usize,
+    #[serde(rename = "max-grade")]
+    max_grade: usize,
+    description: Option<String>,
+    visibility: Visibility,
+    tests: Vec<TestReport>,
+}
+
+/// Outputs, exit status and diff are only reported for failing tests
+#[derive(Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct TestReport {
+    coefficient: usize,
+    description: String,
+    success: bool,
+    signal: Option<u32>,
+    /// Duration in seconds
+    duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_status: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actual: Option<String>,
+    /// Unified diff between the expected and actual standard output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visibility: Option<Visibility>,
+}
+
+/// Run the tests described in `test_file` on the compiler `dtiger`, and
+/// return the YAML report.
+pub async fn run(test_file: &Path, dtiger: &Path) -> Result<String, RunError> {
+    let description = std::fs::read_to_string(test_file)
+        .map_err(|e| RunError::CannotReadTestFile(e.to_string(), test_file.to_owned()))?;
+    let tests: TestFile = serde_yaml::from_str(&description)
+        .map_err(|e| RunError::CannotReadTestFile(e.to_string(), test_file.to_owned()))?;
+    let dir = test_file.parent().unwrap_or_else(|| Path::new("."));
+    let tmp = tempfile::TempDir::new()
+        .map_err(|e| RunError::CannotRunTest(e, String::from("temporary directory")))?;
+    let default_timeout = tests.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let mut groups = Vec::new();
+    for group in tests.groups {
+        let mut results = Vec::new();
+        for test in group.tests {
+            results.push(run_one(&test, dtiger, dir, tmp.path(), default_timeout).await?);
+        }
+        groups.push(GroupReport {
+            grade: results
+                .iter()
+                .filter(|test| test.success)
+                .map(|test| test.coefficient)
+                .sum(),
+            max_grade: results.iter().map(|test| test.coefficient).sum(),
+            description: group.description,
+            visibility: group.visibility,
+            tests: results,
+        });
+    }
+    Ok(serde_yaml::to_string(&Report {
+        grade: groups.iter().map(|group| group.grade).sum(),
+        max_grade: groups.iter().map(|group| group.max_grade).sum(),
+        groups,
+    })
+    .unwrap())
+}
+
+async fn run_one(
+    test: &TestSpec,
+    dtiger: &Path,
+    dir: &Path,
+    tmp: &Path,
+    default_timeout: u64,
+) -> Result<TestReport, RunError> {
+    let cannot_run = |e| RunError::CannotRunTest(e, test.description.clone());
+    let mut command = Command::new(dtiger);
+    command.args(&test.args);
+    if let Some(ref file) = test.file {
+        command.arg(dir.join(file));
+    } else if let Some(ref source) = test.source {
+        let file = tmp.join("test.tig");
+        std::fs::write(&file, source).map_err(cannot_run)?;
+        command.arg(file);
+    }
+    log::debug!("running test {:?}: {command:?}", test.description);
+    let start = Instant::now();
+    let mut child = command
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(cannot_run)?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = test.stdin.clone().unwrap_or_default();
+    // The compiler may exit without reading its input
+    let feed = async move {
+        let _ = stdin.write_all(input.as_bytes()).await;
+    };
+    let timeout = Duration::from_secs(test.timeout.unwrap_or(default_timeout));
+    let (_, output) = tokio::join!(
+        feed,
+        tokio::time::timeout(timeout, child.wait_with_output())
+    );
+    let duration = start.elapsed().as_secs_f64();
+    let Ok(output) = output else {
+        log::info!("test {:?} timed out", test.description);
+        return Ok(TestReport {
+            coefficient: test.coefficient,
+            description: test.description.clone(),
+            success: false,
+            signal: Some(SIGKILL),
+            duration,
+            visibility: test.visibility,
+            ..TestReport::default()
+        });
+    };
+    let output = output.map_err(cannot_run)?;
+    let signal = output.status.signal().map(|s| s as u32);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let status_ok = output.status.code() == Some(test.expected_status);
+    let stdout_ok = test
+        .expected_stdout
+        .as_ref()
+        .is_none_or(|expected| *expected == stdout);
+    let stderr_ok = test
+        .expected_stderr
+        .as_ref()
+        .is_none_or(|expected| stderr.contains(expected.as_str()));
+    let mut report = TestReport {
+        coefficient: test.coefficient,
+        description: test.description.clone(),
+        success: signal.is_none() && status_ok && stdout_ok && stderr_ok,
+        signal,
+        duration,
+        visibility: test.visibility,
+        ..TestReport::default()
+    };
+    if report.success {
+        return Ok(report);
+    }
+    report.status = output.status.code();
+    if !status_ok {
+        report.expected_status = Some(test.expected_status);
+    }
+    if let (false, Some(expected)) = (stdout_ok, &test.expected_stdout) {
+        report.expected = Some(excerpt(expected));
+        report.actual = Some(excerpt(&stdout));
+        report.diff = Some(excerpt(
+            &TextDiff::from_lines(expected.as_str(), &stdout)
+                .unified_diff()
+                .header("expected", "actual")
+                .to_string(),
+        ));
+    } else if let (false, Some(expected)) = (stderr_ok, &test.expected_stderr) {
+        report.expected = Some(excerpt(expected));
+        report.actual = Some(excerpt(&stderr));
+    } else if !stderr.is_empty() {
+        report.actual = Some(excerpt(&stderr));
+    }
+    Ok(report)
+}
+
+/// Keep at most the first `MAX_EXCERPT_SIZE` bytes of `text`
+fn excerpt(text: &str) -> String {
+    if text.len() <= MAX_EXCERPT_SIZE {
+        return text.to_owned();
+    }
+    let mut end = MAX_EXCERPT_SIZE;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n[…]", &text[..end])
+}
+
+#[tokio::test]
+async fn test_run() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let test_file = dir.path().join("lab.yml");
+    std::fs::write(
+        &test_file,
+        r#"
+timeout: 1
+groups:
+  - description: "Shell"
+    tests:
+      - description: "echo"
+        args: ["-c", "echo hi"]
+        expected-stdout: "hi\n"
+      - description: "stdin"
+        coefficient: 2
+        args: ["-c", "read x; echo $x >&2; exit 3"]
+        stdin: "oops\n"
+        expected-status: 3
+        expected-stderr: "oops"
+      - description: "source"
+        source: "echo right"
+        expected-stdout: "wrong\n"
+  - tests:
+      - description: "crash"
+        args: ["-c", "kill -SEGV $$"]
+      - description: "loop"
+        args: ["-c", "sleep 5"]
+"#,
+    )
+    .unwrap();
+    let report: serde_yaml::Value =
+        serde_yaml::from_str(&run(&test_file, Path::new("/bin/sh")).await.unwrap()).unwrap();
+    assert_eq!(report["grade"], 3);
+    assert_eq!(report["max-grade"], 6);
+    let tests = &report["groups"][0]["tests"];
+    assert_eq!(tests[0]["success"], true);
+    assert_eq!(tests[1]["success"], true);
+    assert_eq!(tests[2]["success"], false);
+    assert_eq!(tests[2]["expected"], "wrong\n");
+    assert_eq!(
+        tests[2]["diff"],
+        "--- expected\n+++ actual\n@@ -1 +1 @@\n-wrong\n+right\n"
+    );
+    let tests = &report["groups"][1]["tests"];
+    assert_eq!(tests[0]["signal"], 11);
+    assert_eq!(tests[1]["signal"], SIGKILL);
+}
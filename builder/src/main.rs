@@ -1,6 +1,9 @@
+mod buildsystem;
 mod commands;
+mod labtests;
 mod outputs;
 
+use buildsystem::BuildSystem;
 use clap::Parser;
 use graders_utils::ziputils::unzip;
 use std::path::{Path, PathBuf};
@@ -8,8 +11,11 @@ use std::path::{Path, PathBuf};
 /// Compile the compiler from the given directory, set env
 /// DTIGER environment variable and run the tests using the
 /// given tester as well as the given configuration file.
+/// With `--native-tests`, the configuration file is run
+/// directly by the builder and the tester is not used.
 ///
-/// It outputs a YAML file with the result.
+/// It outputs a YAML file with the result, including the outputs
+/// of the commands run in a `logs` entry.
 ///
 /// Logging is enabled by setting `RUST_LOG` to the desired
 /// level, possibly restricted to this program:
@@ -28,6 +34,28 @@ pub struct Opt {
     #[clap(short, long)]
     output_file: Option<PathBuf>,
 
+    /// Build system to use instead of detecting it
+    #[clap(long, value_enum)]
+    build_system: Option<BuildSystem>,
+
+    /// Shell command used to build the program instead of the build system
+    /// ones (may be repeated)
+    #[clap(long)]
+    build_command: Vec<String>,
+
+    /// Path of the compiler executable relative to the source directory,
+    /// instead of the build system default one
+    #[clap(long)]
+    executable: Option<PathBuf>,
+
+    /// Run the tests from the test file without using the test driver command
+    #[clap(long)]
+    native_tests: bool,
+
+    /// Maximum size in bytes of each output kept in the logs of a command
+    #[clap(long, default_value_t = 16384)]
+    max_log_size: usize,
+
     /// Compiler source (directory, zip file, or URL of zip file)
     src: String,
 
@@ -55,14 +83,23 @@ async fn main() {
         match unzip(&tmp.keep(), &opt.src, top_level_dir).await {
             Ok(d) => opt.src = d.to_str().unwrap().to_owned(), // Replace src by directory
             Err(e) => {
-                outputs::write_error(&opt, e.into());
+                let kind = e.kind();
+                outputs::write_error(&opt, e.into(), kind, &[]);
                 return;
             }
         }
     }
-    let dtiger = Path::new(&opt.src).join("src/driver/dtiger");
-    match commands::build(&opt).and_then(|_| commands::run_test(&opt, &dtiger)) {
-        Ok(output) => outputs::write_output(&opt, &output),
-        Err(e) => outputs::write_error(&opt, e.into()),
+    let mut logs = Vec::new();
+    let result = match commands::build(&opt, &mut logs) {
+        Ok(dtiger) if opt.native_tests => labtests::run(&opt.test_file, &dtiger).await,
+        Ok(dtiger) => commands::run_test(&opt, &dtiger, &mut logs),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(output) => outputs::write_output(&opt, &outputs::with_logs(&output, &logs)),
+        Err(e) => {
+            let kind = e.kind();
+            outputs::write_error(&opt, e.into(), kind, &logs);
+        }
     }
 }
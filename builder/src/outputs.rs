@@ -1,3 +1,5 @@
+use graders_utils::errorkind::ErrorKind;
+use graders_utils::logs::{LOGS_KEY, PhaseLog};
 use serde_derive::Serialize;
 use std::fs::File;
 use std::io::{self, Write};
@@ -7,11 +9,15 @@ use std::process::exit;
 use super::Opt;
 
 #[derive(Serialize)]
-struct Output {
+struct Output<'a> {
     grade: u32,
     #[serde(rename = "max-grade")]
     max_grade: u32,
     explanation: String,
+    #[serde(rename = "error-kind")]
+    error_kind: ErrorKind,
+    #[serde(skip_serializing_if = "<[PhaseLog]>::is_empty")]
+    logs: &'a [PhaseLog],
 }
 
 fn write_file<P: AsRef<Path>>(file: P, output: &str) -> io::Result<()> {
@@ -30,13 +36,33 @@ pub fn write_output(opt: &Opt, output: &str) {
     }
 }
 
-pub fn write_error(opt: &Opt, error: eyre::Report) {
+/// Add `logs` to the YAML test results. If they cannot be parsed as
+/// a mapping, they are returned unchanged.
+pub fn with_logs(output: &str, logs: &[PhaseLog]) -> String {
+    if logs.is_empty() {
+        return output.to_owned();
+    }
+    match serde_yaml::from_str::<serde_yaml::Value>(output) {
+        Ok(serde_yaml::Value::Mapping(mut mapping)) => {
+            mapping.insert(LOGS_KEY.into(), serde_yaml::to_value(logs).unwrap());
+            serde_yaml::to_string(&mapping).unwrap()
+        }
+        _ => {
+            log::warn!("test results are not a YAML mapping, logs will not be added");
+            output.to_owned()
+        }
+    }
+}
+
+pub fn write_error(opt: &Opt, error: eyre::Report, error_kind: ErrorKind, logs: &[PhaseLog]) {
     write_output(
         opt,
         &serde_yaml::to_string(&Output {
             grade: 0,
             max_grade: 1,
             explanation: error.to_string(),
+            error_kind,
+            logs,
         })
         .unwrap(),
     );
@@ -1,48 +1,78 @@
-use amqp_utils::{self, AmqpChannel, AmqpConnection, AmqpError, AmqpRequest, AmqpResponse};
+use amqp_utils::{
+    self, AmqpChannel, AmqpError, AmqpRequest, AmqpResponse, AmqpSupervisor, Envelope,
+};
 use futures::channel::mpsc::{Receiver, Sender};
-use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt, TryStreamExt, future, try_join};
+use futures::{FutureExt, SinkExt, StreamExt, TryStreamExt, future, try_join};
 use std::sync::Arc;
 
 use crate::config::Configuration;
-use crate::gitlab;
+use crate::package;
 
+/// Header holding the lab name, to help inspecting jobs with AMQP tools
+static LAB_HEADER: &str = "x-grader-lab";
+
+/// Publish job requests. A request whose publication failed is kept in
+/// `pending` so that it can be published again after a reconnection.
 async fn amqp_publisher(
     channel: AmqpChannel,
     config: &Arc<Configuration>,
-    receive_request: Receiver<AmqpRequest>,
+    receive_request: &mut Receiver<AmqpRequest>,
+    pending: &mut Option<Envelope<AmqpRequest>>,
 ) -> Result<(), AmqpError> {
-    receive_request
-        .map(Ok)
-        .try_for_each(|req| {
-            let channel = channel.clone();
-            let config = config.clone();
-            async move {
-                log::info!("publishing AMQP job request {}", req.job_name);
-                channel
-                    .basic_publish(&config.amqp.exchange, &config.amqp.routing_key, &req)
-                    .await
-            }
-        })
-        .await
+    loop {
+        let req = match pending.take() {
+            Some(req) => req,
+            None => match receive_request.next().await {
+                Some(req) => Envelope::new(crate::PRODUCER, req),
+                None => return Ok(()),
+            },
+        };
+        log::info!("publishing AMQP job request {}", req.payload.job_name);
+        let properties = req
+            .properties()
+            .with_reply_to(package::RESULT_QUEUE)
+            .with_header(LAB_HEADER, &req.payload.lab);
+        if let Err(e) = channel
+            .basic_publish_confirmed(
+                &config.amqp.exchange,
+                &config.amqp.routing_key,
+                &req,
+                &properties,
+            )
+            .await
+        {
+            *pending = Some(req);
+            return Err(e);
+        }
+    }
 }
 
 async fn amqp_receiver(
     channel: AmqpChannel,
     send_response: Sender<AmqpResponse>,
 ) -> Result<(), AmqpError> {
-    channel.queue_declare_durable(gitlab::RESULT_QUEUE).await?;
+    channel.queue_declare_durable(package::RESULT_QUEUE).await?;
     let stream = channel
-        .basic_consume(gitlab::RESULT_QUEUE, "gitlab-to-amqp")
+        .basic_consume(package::RESULT_QUEUE, "gitlab-to-amqp")
         .await?;
-    log::info!("listening onto the {} queue", gitlab::RESULT_QUEUE);
+    log::info!("listening onto the {} queue", package::RESULT_QUEUE);
     let data = stream
         .err_into()
         .and_then(|msg| {
             let channel = channel.clone();
             async move {
                 channel.basic_ack(msg.delivery_tag()).await?;
-                let response = msg.decode_payload()?;
-                Ok(response)
+                let mut response: Envelope<AmqpResponse> = msg.decode_payload()?;
+                if response.correlation_id.is_none() {
+                    response.correlation_id = msg.correlation_id().map(str::to_owned);
+                }
+                log::debug!(
+                    "received response {} (schema version {}) to request {:?}",
+                    response.payload.job_name,
+                    response.schema_version,
+                    response.correlation_id
+                );
+                Ok(response.payload)
             }
         })
         .filter(|r| future::ready(r.is_ok()));
@@ -56,30 +86,36 @@ async fn amqp_receiver(
         .inspect(|_| {
             log::warn!(
                 "terminating listening onto the {} queue",
-                gitlab::RESULT_QUEUE
+                package::RESULT_QUEUE
             );
         })
         .await?;
-    Ok(())
+    Err(AmqpError::ConsumerCancelled(
+        package::RESULT_QUEUE.to_owned(),
+    ))
 }
 
 #[allow(clippy::module_name_repetitions)]
 pub async fn amqp_process(
     config: &Arc<Configuration>,
-    receive_request: Receiver<AmqpRequest>,
+    mut receive_request: Receiver<AmqpRequest>,
     send_response: Sender<AmqpResponse>,
 ) -> Result<(), AmqpError> {
-    let conn = AmqpConnection::new(&config.amqp).await?;
-    let config = config.clone();
-    let publisher = {
-        let channel = conn.create_channel().await?;
-        channel.declare_exchange_and_queue(&config.amqp).await?;
-        amqp_publisher(channel, &config, receive_request)
-    };
-    let receiver = {
-        let channel = conn.create_channel().await?;
-        amqp_receiver(channel, send_response)
-    };
-    try_join!(publisher.err_into(), receiver)?;
-    Ok(())
+    let mut pending = None;
+    AmqpSupervisor::new(&config.amqp)
+        .run(async |conn| {
+            let publisher = {
+                let channel = conn.create_channel().await?;
+                channel.declare_exchange_and_queue(&config.amqp).await?;
+                channel.confirm_select().await?;
+                amqp_publisher(channel, config, &mut receive_request, &mut pending)
+            };
+            let receiver = {
+                let channel = conn.create_channel().await?;
+                amqp_receiver(channel, send_response.clone())
+            };
+            try_join!(publisher, receiver)?;
+            Ok(())
+        })
+        .await
 }
@@ -0,0 +1,96 @@
+use hyper::Request;
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap};
+use url::Url;
+
+use super::github::{from_event, verify_signature};
+use super::{Credentials, Forge, ForgeKind, Hook, HookError, State, Token};
+use crate::config::ForgeConfiguration;
+
+static GITEA_USERNAME: &str = "grader";
+
+pub struct Gitea<'a>(pub &'a ForgeConfiguration);
+
+fn base_api(config: &ForgeConfiguration) -> Url {
+    config.base_url.join("api/v1/").unwrap()
+}
+
+fn make_post(
+    config: &ForgeConfiguration,
+    fragment: &str,
+    body: &serde_json::Value,
+) -> Request<String> {
+    let uri = base_api(config).join(fragment).unwrap();
+    let body = body.to_string();
+    Request::post(uri.to_string())
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+impl Forge for Gitea<'_> {
+    fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), HookError> {
+        let Some(ref secret_token) = self.0.secret_token else {
+            return Ok(());
+        };
+        let signature = headers
+            .get("X-Gitea-Signature")
+            .and_then(|h| h.to_str().ok());
+        verify_signature(secret_token, signature, body)
+    }
+
+    fn parse_hook(&self, headers: &HeaderMap, body: &[u8]) -> Result<Option<Hook>, HookError> {
+        let event = headers.get("X-Gitea-Event").and_then(|h| h.to_str().ok());
+        Ok(from_event(ForgeKind::Gitea, event, body)?)
+    }
+
+    fn credentials(&self) -> eyre::Result<Credentials> {
+        if self.0.token.is_empty() {
+            eyre::bail!("no token configured for gitea");
+        }
+        Ok(Credentials {
+            header: (AUTHORIZATION, "token "),
+            username: self
+                .0
+                .clone_username
+                .clone()
+                .unwrap_or_else(|| GITEA_USERNAME.to_owned()),
+            token: Token::Static(self.0.token.clone()),
+        })
+    }
+
+    fn post_status(
+        &self,
+        hook: &Hook,
+        state: &State,
+        name: &str,
+        description: Option<&str>,
+    ) -> Request<String> {
+        let state = match state {
+            State::Running => "pending",
+            State::Success => "success",
+            State::Failed => "failure",
+            State::Canceled => "error",
+        };
+        make_post(
+            self.0,
+            &format!("repos/{}/statuses/{}", hook.project_id, hook.pushed_sha()),
+            &serde_json::json!({
+                "state": state,
+                "context": name,
+                "description": description.unwrap_or_default(),
+            }),
+        )
+    }
+
+    /// Gitea cannot comment on commits, the report is only posted on pull
+    /// requests
+    fn post_comment(&self, hook: &Hook, note: &str) -> Option<Request<String>> {
+        let mr = hook.merge_request.as_ref()?;
+        Some(make_post(
+            self.0,
+            &format!("repos/{}/issues/{}/comments", mr.target_project_id, mr.iid),
+            &serde_json::json!({ "body": note }),
+        ))
+    }
+}
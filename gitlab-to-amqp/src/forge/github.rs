@@ -0,0 +1,499 @@
+use base64::prelude::{BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, FixedOffset};
+use graders_utils::time::unix_time;
+use hmac::{Hmac, KeyInit, Mac};
+use http_body_util::{BodyExt, Full};
+use hyper::Request;
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, USER_AGENT};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::fs;
+use tokio::sync::Mutex;
+use url::Url;
+
+use super::{
+    Credentials, Forge, ForgeKind, Hook, HookError, MergeRequest, Repository, State, Token,
+};
+use crate::client::HttpClient;
+use crate::config::{ForgeConfiguration, GithubAppConfiguration};
+
+static GITHUB_USERNAME: &str = "x-access-token";
+
+/// Installation tokens are renewed when they expire in less than this
+/// number of seconds
+const TOKEN_RENEWAL_MARGIN: u64 = 300;
+
+pub struct Github<'a>(pub &'a ForgeConfiguration);
+
+/// Installation tokens of a GitHub App, which expire after one hour
+pub struct AppTokens {
+    base_url: Url,
+    app_id: u64,
+    installation_id: u64,
+    key: PKey<Private>,
+    /// Current token, and its expiration time in seconds since the Unix epoch
+    current: Mutex<Option<(String, u64)>>,
+}
+
+#[derive(Deserialize)]
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<FixedOffset>,
+}
+
+impl AppTokens {
+    pub fn new(base_url: &Url, app: &GithubAppConfiguration) -> eyre::Result<AppTokens> {
+        let key = PKey::private_key_from_pem(&fs::read(&app.private_key)?)?;
+        Ok(AppTokens {
+            base_url: base_url.clone(),
+            app_id: app.app_id,
+            installation_id: app.installation_id,
+            key,
+            current: Mutex::new(None),
+        })
+    }
+
+    /// JSON Web Token authenticating the App itself, valid for ten minutes
+    fn jwt(&self, now: u64) -> eyre::Result<String> {
+        let encode = |data: &[u8]| BASE64_URL_SAFE_NO_PAD.encode(data);
+        let header = encode(br#"{"alg":"RS256","typ":"JWT"}"#);
+        // Issued in the past to allow for clock drift, as recommended by GitHub
+        let claims = serde_json::json!({
+            "iat": now.saturating_sub(60),
+            "exp": now + 540,
+            "iss": self.app_id.to_string(),
+        });
+        let message = format!("{header}.{}", encode(claims.to_string().as_bytes()));
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(message.as_bytes())?;
+        Ok(format!("{message}.{}", encode(&signer.sign_to_vec()?)))
+    }
+
+    /// Return the current installation token, minting a new one when it
+    /// is about to expire
+    pub async fn token(&self, client: &HttpClient) -> eyre::Result<String> {
+        let mut current = self.current.lock().await;
+        let now = unix_time();
+        if let Some((ref token, expires_at)) = *current {
+            if now + TOKEN_RENEWAL_MARGIN < expires_at {
+                return Ok(token.clone());
+            }
+        }
+        log::debug!(
+            "requesting a new token for installation {}",
+            self.installation_id
+        );
+        let uri = self.base_url.join(&format!(
+            "app/installations/{}/access_tokens",
+            self.installation_id
+        ))?;
+        let request = Request::post(uri.to_string())
+            .header(AUTHORIZATION, format!("Bearer {}", self.jwt(now)?))
+            .header(ACCEPT, "application/vnd.github+json")
+            .header(USER_AGENT, crate::PRODUCER)
+            .body(Full::new(Bytes::new()))?;
+        let response = client.request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            eyre::bail!("cannot obtain an installation token: status {status}");
+        }
+        let token: InstallationToken = serde_json::from_slice(&body)?;
+        let expires_at = u64::try_from(token.expires_at.timestamp()).unwrap_or_default();
+        *current = Some((token.token.clone(), expires_at));
+        Ok(token.token)
+    }
+}
+
+/// Push event, also used by Gitea
+#[derive(Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    ref_: String,
+    /// Zeroes when the reference is deleted
+    after: String,
+    #[serde(default)]
+    deleted: bool,
+    repository: EventRepository,
+}
+
+#[derive(Deserialize)]
+struct PullRequestEvent {
+    action: String,
+    number: u32,
+    pull_request: PullRequest,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    head: Branch,
+    base: Branch,
+}
+
+#[derive(Deserialize)]
+struct Branch {
+    #[serde(rename = "ref")]
+    ref_: String,
+    sha: String,
+    repo: EventRepository,
+}
+
+#[derive(Deserialize)]
+struct EventRepository {
+    name: String,
+    full_name: String,
+    html_url: Url,
+    clone_url: Url,
+}
+
+impl From<EventRepository> for Repository {
+    fn from(repository: EventRepository) -> Repository {
+        Repository {
+            name: repository.name,
+            homepage: repository.html_url,
+            git_http_url: repository.clone_url,
+        }
+    }
+}
+
+/// Decode a push or pull request event, whose name is given by `event`.
+/// GitHub and Gitea share the same payloads.
+pub(super) fn from_event(
+    forge: ForgeKind,
+    event: Option<&str>,
+    body: &[u8],
+) -> serde_json::Result<Option<Hook>> {
+    match event {
+        Some("push") => {
+            let event: PushEvent = serde_json::from_slice(body)?;
+            let deleted = event.deleted || event.after.bytes().all(|b| b == b'0');
+            Ok(Some(Hook {
+                forge,
+                object_kind: String::from(if event.ref_.starts_with("refs/tags/") {
+                    "tag_push"
+                } else {
+                    "push"
+                }),
+                checkout_sha: (!deleted).then_some(event.after),
+                project_id: event.repository.full_name.clone(),
+                ref_: event.ref_,
+                repository: event.repository.into(),
+                merge_request: None,
+                submission: None,
+                received_at: Some(unix_time()),
+            }))
+        }
+        Some("pull_request") => {
+            let event: PullRequestEvent = serde_json::from_slice(body)?;
+            let pr = event.pull_request;
+            let action = match event.action.as_str() {
+                "opened" => "open",
+                "reopened" => "reopen",
+                "synchronize" | "synchronized" => "update",
+                action => action,
+            };
+            Ok(Some(Hook {
+                forge,
+                object_kind: String::from("merge_request"),
+                checkout_sha: Some(pr.head.sha),
+                project_id: pr.head.repo.full_name.clone(),
+                ref_: format!("refs/heads/{}", pr.head.ref_),
+                repository: pr.head.repo.into(),
+                merge_request: Some(MergeRequest {
+                    iid: event.number,
+                    target_project_id: pr.base.repo.full_name,
+                    target_branch: pr.base.ref_,
+                    action: Some(action.to_owned()),
+                    // Synchronization events are only sent for new commits
+                    new_commits: true,
+                }),
+                submission: None,
+                received_at: Some(unix_time()),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Check the hexadecimal HMAC-SHA256 `signature` of `body`
+pub(super) fn verify_signature(
+    secret: &str,
+    signature: Option<&str>,
+    body: &[u8],
+) -> Result<(), HookError> {
+    let signature = signature.ok_or(HookError::MissingSecret)?;
+    let signature = hex::decode(signature).map_err(|_| HookError::IncorrectSecret)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| HookError::IncorrectSecret)
+}
+
+fn make_post(
+    config: &ForgeConfiguration,
+    fragment: &str,
+    body: &serde_json::Value,
+) -> Request<String> {
+    let uri = config.base_url.join(fragment).unwrap();
+    let body = body.to_string();
+    Request::post(uri.to_string())
+        .header(ACCEPT, "application/vnd.github+json")
+        .header(USER_AGENT, crate::PRODUCER)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+impl Forge for Github<'_> {
+    fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), HookError> {
+        let Some(ref secret_token) = self.0.secret_token else {
+            return Ok(());
+        };
+        let signature = headers
+            .get("X-Hub-Signature-256")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.strip_prefix("sha256=").unwrap_or(h));
+        verify_signature(secret_token, signature, body)
+    }
+
+    fn parse_hook(&self, headers: &HeaderMap, body: &[u8]) -> Result<Option<Hook>, HookError> {
+        let event = headers.get("X-GitHub-Event").and_then(|h| h.to_str().ok());
+        Ok(from_event(ForgeKind::Github, event, body)?)
+    }
+
+    fn credentials(&self) -> eyre::Result<Credentials> {
+        let token = match self.0.app {
+            Some(ref app) => Token::GithubApp(AppTokens::new(&self.0.base_url, app)?),
+            None if self.0.token.is_empty() => {
+                eyre::bail!("no token or app configured for github")
+            }
+            None => Token::Static(self.0.token.clone()),
+        };
+        Ok(Credentials {
+            header: (AUTHORIZATION, "Bearer "),
+            username: self
+                .0
+                .clone_username
+                .clone()
+                .unwrap_or_else(|| GITHUB_USERNAME.to_owned()),
+            token,
+        })
+    }
+
+    /// Statuses are check runs when an App is configured, as check runs
+    /// can only be created by Apps, and commit statuses otherwise
+    fn post_status(
+        &self,
+        hook: &Hook,
+        state: &State,
+        name: &str,
+        description: Option<&str>,
+    ) -> Request<String> {
+        if self.0.app.is_none() {
+            let state = match state {
+                State::Running => "pending",
+                State::Success => "success",
+                State::Failed => "failure",
+                State::Canceled => "error",
+            };
+            return make_post(
+                self.0,
+                &format!("repos/{}/statuses/{}", hook.project_id, hook.pushed_sha()),
+                &serde_json::json!({
+                    "state": state,
+                    "context": name,
+                    "description": description.unwrap_or_default(),
+                }),
+            );
+        }
+        let mut check_run = serde_json::json!({
+            "name": name,
+            "head_sha": hook.pushed_sha(),
+        });
+        match state {
+            State::Running => check_run["status"] = "in_progress".into(),
+            State::Success | State::Failed | State::Canceled => {
+                check_run["status"] = "completed".into();
+                check_run["conclusion"] = match state {
+                    State::Success => "success",
+                    State::Failed => "failure",
+                    _ => "cancelled",
+                }
+                .into();
+            }
+        }
+        if let Some(description) = description {
+            check_run["output"] = serde_json::json!({
+                "title": description,
+                "summary": description,
+            });
+        }
+        make_post(
+            self.0,
+            &format!("repos/{}/check-runs", hook.project_id),
+            &check_run,
+        )
+    }
+
+    fn post_comment(&self, hook: &Hook, note: &str) -> Option<Request<String>> {
+        let fragment = match hook.merge_request {
+            Some(ref mr) => format!("repos/{}/issues/{}/comments", mr.target_project_id, mr.iid),
+            None => format!(
+                "repos/{}/commits/{}/comments",
+                hook.project_id,
+                hook.pushed_sha()
+            ),
+        };
+        Some(make_post(
+            self.0,
+            &fragment,
+            &serde_json::json!({ "body": note }),
+        ))
+    }
+}
+
+#[test]
+fn test_signature() {
+    // Example from the GitHub documentation
+    let secret = "It's a Secret to Everybody";
+    let signature = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+    assert!(verify_signature(secret, Some(signature), b"Hello, World!").is_ok());
+    assert!(matches!(
+        verify_signature(secret, Some(signature), b"Hello, World?"),
+        Err(HookError::IncorrectSecret)
+    ));
+    assert!(matches!(
+        verify_signature(secret, None, b"Hello, World!"),
+        Err(HookError::MissingSecret)
+    ));
+}
+
+#[test]
+fn test_pull_request_hook() {
+    let repo = |owner: &str| {
+        serde_json::json!({
+            "name": "compiler",
+            "full_name": format!("{owner}/compiler"),
+            "html_url": format!("https://github.com/{owner}/compiler"),
+            "clone_url": format!("https://github.com/{owner}/compiler.git"),
+        })
+    };
+    let body = serde_json::json!({
+        "action": "synchronize",
+        "number": 3,
+        "pull_request": {
+            "head": {"ref": "lab3", "sha": "fedcba9876543210fedcba9876543210fedcba98", "repo": repo("student")},
+            "base": {"ref": "main", "sha": "0123456789abcdef0123456789abcdef01234567", "repo": repo("school")},
+        },
+    });
+    let hook = from_event(
+        ForgeKind::Github,
+        Some("pull_request"),
+        body.to_string().as_bytes(),
+    )
+    .unwrap()
+    .unwrap();
+    assert!(hook.is_gradable());
+    assert_eq!(hook.project_id, "student/compiler");
+    assert_eq!(hook.desc(), "compiler (#3 lab3 - fedcba98)");
+    assert_eq!(hook.target_branch(), Some("main"));
+    let mut config = ForgeConfiguration {
+        base_url: Url::parse("https://api.github.com/").unwrap(),
+        token: String::from("token"),
+        secret_token: None,
+        clone_username: None,
+        app: None,
+    };
+    let comment = Github(&config).post_comment(&hook, "report").unwrap();
+    assert_eq!(
+        comment.uri(),
+        "https://api.github.com/repos/school/compiler/issues/3/comments"
+    );
+    // Without an App, results are reported as commit statuses
+    let status = Github(&config).post_status(&hook, &State::Running, "lab3", None);
+    assert_eq!(
+        status.uri(),
+        "https://api.github.com/repos/student/compiler/statuses/fedcba9876543210fedcba9876543210fedcba98"
+    );
+    let status: serde_json::Value = serde_json::from_str(status.body()).unwrap();
+    assert_eq!(status["state"], "pending");
+    assert_eq!(status["context"], "lab3");
+    config.app = Some(GithubAppConfiguration {
+        app_id: 1,
+        installation_id: 2,
+        private_key: "app.pem".into(),
+    });
+    let status = Github(&config).post_status(&hook, &State::Failed, "lab3", Some("grade: 1/2"));
+    assert_eq!(
+        status.uri(),
+        "https://api.github.com/repos/student/compiler/check-runs"
+    );
+    let status: serde_json::Value = serde_json::from_str(status.body()).unwrap();
+    assert_eq!(status["status"], "completed");
+    assert_eq!(status["conclusion"], "failure");
+    assert_eq!(status["output"]["summary"], "grade: 1/2");
+    let status = Github(&config).post_status(
+        &hook,
+        &State::Running,
+        "lab3",
+        Some("packaging and testing"),
+    );
+    let status: serde_json::Value = serde_json::from_str(status.body()).unwrap();
+    assert_eq!(status["status"], "in_progress");
+    assert_eq!(
+        status["head_sha"],
+        "fedcba9876543210fedcba9876543210fedcba98"
+    );
+    assert!(status.get("conclusion").is_none());
+    let body = serde_json::json!({
+        "ref": "refs/heads/main",
+        "after": "0000000000000000000000000000000000000000",
+        "deleted": true,
+        "repository": repo("student"),
+    });
+    let hook = from_event(ForgeKind::Github, Some("push"), body.to_string().as_bytes())
+        .unwrap()
+        .unwrap();
+    assert!(!hook.is_gradable());
+    assert!(
+        from_event(ForgeKind::Github, Some("ping"), b"{}")
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn test_app_jwt() {
+    let key = openssl::rsa::Rsa::generate(2048).unwrap();
+    let mut pem = tempfile::NamedTempFile::new().unwrap();
+    std::io::Write::write_all(&mut pem, &key.private_key_to_pem().unwrap()).unwrap();
+    let app = GithubAppConfiguration {
+        app_id: 1234,
+        installation_id: 5678,
+        private_key: pem.path().to_owned(),
+    };
+    let tokens = AppTokens::new(&Url::parse("https://api.github.com/").unwrap(), &app).unwrap();
+    let jwt = tokens.jwt(1_000_000).unwrap();
+    let parts = jwt.split('.').collect::<Vec<_>>();
+    assert_eq!(parts.len(), 3);
+    let claims: serde_json::Value =
+        serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+    assert_eq!(claims["iss"], "1234");
+    assert_eq!(claims["iat"], 999_940);
+    assert_eq!(claims["exp"], 1_000_540);
+    let public = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+    let mut verifier = openssl::sign::Verifier::new(MessageDigest::sha256(), &public).unwrap();
+    verifier
+        .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+        .unwrap();
+    assert!(
+        verifier
+            .verify(&BASE64_URL_SAFE_NO_PAD.decode(parts[2]).unwrap())
+            .unwrap()
+    );
+}
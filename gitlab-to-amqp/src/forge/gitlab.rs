@@ -0,0 +1,236 @@
+use graders_utils::time::unix_time;
+use hyper::Request;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderName};
+use serde::Deserialize;
+use std::borrow::Borrow;
+use url::{Url, form_urlencoded};
+
+use super::{
+    Credentials, Forge, ForgeKind, Hook, HookError, MergeRequest, Repository, State, Token,
+};
+use crate::config::GitlabConfiguration;
+
+static GITLAB_USERNAME: &str = "grader";
+
+pub struct Gitlab<'a>(pub &'a GitlabConfiguration);
+
+#[derive(Deserialize)]
+struct MergeRequestEvent {
+    object_attributes: MergeRequestAttributes,
+}
+
+#[derive(Deserialize)]
+struct MergeRequestAttributes {
+    iid: u32,
+    action: Option<String>,
+    /// Previous head, only present when an update brings new commits
+    oldrev: Option<String>,
+    source_branch: String,
+    target_branch: String,
+    source_project_id: u32,
+    target_project_id: u32,
+    last_commit: MergeRequestCommit,
+    source: Repository,
+}
+
+#[derive(Deserialize)]
+struct MergeRequestCommit {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ObjectKind {
+    object_kind: String,
+}
+
+/// Decode a push, tag push or merge request webhook payload
+fn from_event(body: &[u8]) -> serde_json::Result<Option<Hook>> {
+    match serde_json::from_slice::<ObjectKind>(body)?
+        .object_kind
+        .as_str()
+    {
+        "push" | "tag_push" => {
+            let mut hook: Hook = serde_json::from_slice(body)?;
+            hook.received_at = Some(unix_time());
+            Ok(Some(hook))
+        }
+        "merge_request" => {
+            let event: MergeRequestEvent = serde_json::from_slice(body)?;
+            let mr = event.object_attributes;
+            Ok(Some(Hook {
+                forge: ForgeKind::Gitlab,
+                object_kind: String::from("merge_request"),
+                checkout_sha: Some(mr.last_commit.id),
+                project_id: mr.source_project_id.to_string(),
+                ref_: format!("refs/heads/{}", mr.source_branch),
+                repository: mr.source,
+                merge_request: Some(MergeRequest {
+                    iid: mr.iid,
+                    target_project_id: mr.target_project_id.to_string(),
+                    target_branch: mr.target_branch,
+                    action: mr.action,
+                    new_commits: mr.oldrev.is_some(),
+                }),
+                submission: None,
+                received_at: Some(unix_time()),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn base_api(config: &GitlabConfiguration) -> Url {
+    config.base_url.join("api/v4/").unwrap()
+}
+
+fn make_post<I, K, V>(config: &GitlabConfiguration, fragment: &str, params: I) -> Request<String>
+where
+    I: IntoIterator,
+    I::Item: Borrow<(K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let uri = base_api(config).join(fragment).unwrap();
+    let params = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+    Request::post(uri.to_string())
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(CONTENT_LENGTH, params.len())
+        .body(params)
+        .unwrap()
+}
+
+impl Forge for Gitlab<'_> {
+    fn verify(&self, headers: &HeaderMap, _body: &[u8]) -> Result<(), HookError> {
+        let Some(ref secret_token) = self.0.secret_token else {
+            return Ok(());
+        };
+        match headers.get("X-Gitlab-Token").and_then(|h| h.to_str().ok()) {
+            Some(from_request) if from_request == secret_token => Ok(()),
+            Some(_) => Err(HookError::IncorrectSecret),
+            None => Err(HookError::MissingSecret),
+        }
+    }
+
+    fn parse_hook(&self, _headers: &HeaderMap, body: &[u8]) -> Result<Option<Hook>, HookError> {
+        Ok(from_event(body)?)
+    }
+
+    fn credentials(&self) -> eyre::Result<Credentials> {
+        Ok(Credentials {
+            header: (HeaderName::from_static("private-token"), ""),
+            username: GITLAB_USERNAME.to_owned(),
+            token: Token::Static(self.0.token.clone()),
+        })
+    }
+
+    fn post_status(
+        &self,
+        hook: &Hook,
+        state: &State,
+        name: &str,
+        description: Option<&str>,
+    ) -> Request<String> {
+        let state = format!("{state}");
+        let mut params: Vec<(&str, &str)> = vec![("state", &state), ("name", name)];
+        if let Some(r) = hook.ref_name() {
+            params.push(("ref", r));
+        }
+        if let Some(d) = description {
+            params.push(("description", d));
+        }
+        make_post(
+            self.0,
+            &format!(
+                "projects/{}/statuses/{}",
+                hook.project_id,
+                hook.pushed_sha()
+            ),
+            &params,
+        )
+    }
+
+    fn post_comment(&self, hook: &Hook, note: &str) -> Option<Request<String>> {
+        if let Some(ref mr) = hook.merge_request {
+            return Some(make_post(
+                self.0,
+                &format!(
+                    "projects/{}/merge_requests/{}/notes",
+                    mr.target_project_id, mr.iid
+                ),
+                &vec![("body", note)],
+            ));
+        }
+        Some(make_post(
+            self.0,
+            &format!(
+                "projects/{}/repository/commits/{}/comments",
+                hook.project_id,
+                hook.pushed_sha()
+            ),
+            &vec![("note", note)],
+        ))
+    }
+}
+
+#[test]
+fn test_merge_request_hook() {
+    let body = br#"{
+        "object_kind": "merge_request",
+        "event_type": "merge_request",
+        "project": {"id": 1, "name": "compiler"},
+        "object_attributes": {
+            "iid": 7,
+            "action": "update",
+            "oldrev": "0123456789abcdef0123456789abcdef01234567",
+            "source_branch": "lab3",
+            "target_branch": "submission",
+            "source_project_id": 2,
+            "target_project_id": 1,
+            "last_commit": {"id": "fedcba9876543210fedcba9876543210fedcba98"},
+            "source": {
+                "name": "compiler",
+                "homepage": "https://gitlab.example.com/student/compiler",
+                "git_http_url": "https://gitlab.example.com/student/compiler.git"
+            }
+        }
+    }"#;
+    let hook = from_event(body).unwrap().unwrap();
+    assert!(hook.is_gradable());
+    assert_eq!(
+        hook.pushed_sha(),
+        "fedcba9876543210fedcba9876543210fedcba98"
+    );
+    assert_eq!(hook.branch_name(), Some("lab3"));
+    assert_eq!(hook.project_id, "2");
+    assert_eq!(hook.desc(), "compiler (!7 lab3 - fedcba98)");
+    assert_eq!(hook.target_branch(), Some("submission"));
+    let (hook, _) =
+        crate::package::from_opaque(&crate::package::to_opaque(&hook, "job.zip")).unwrap();
+    assert_eq!(hook.merge_request.unwrap().target_project_id, "1");
+}
+
+#[test]
+fn test_tag_push_hook() {
+    let body = br#"{
+        "object_kind": "tag_push",
+        "ref": "refs/tags/submit-lab3",
+        "checkout_sha": "fedcba9876543210fedcba9876543210fedcba98",
+        "project_id": 2,
+        "project": {"id": 2, "name": "compiler"},
+        "repository": {
+            "name": "compiler",
+            "homepage": "https://gitlab.example.com/student/compiler",
+            "git_http_url": "https://gitlab.example.com/student/compiler.git"
+        }
+    }"#;
+    let mut hook = from_event(body).unwrap().unwrap();
+    assert!(!hook.is_gradable());
+    assert_eq!(hook.ref_name(), Some("submit-lab3"));
+    assert_eq!(hook.status_name("lab3"), "lab3");
+    hook.mark_as_submission("lab3".to_owned());
+    assert!(hook.is_gradable());
+    assert_eq!(hook.status_name("lab3"), "lab3 submission");
+    assert!(from_event(br#"{"object_kind": "note"}"#).unwrap().is_none());
+}
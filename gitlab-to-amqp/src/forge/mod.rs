@@ -0,0 +1,345 @@
+#![allow(clippy::module_name_repetitions)]
+
+pub mod gitea;
+pub mod github;
+pub mod gitlab;
+
+use graders_utils::time::unix_time;
+use hyper::Request;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use url::Url;
+
+use crate::client::HttpClient;
+use crate::config::Configuration;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForgeKind {
+    #[default]
+    Gitlab,
+    Github,
+    Gitea,
+}
+
+impl fmt::Display for ForgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                ForgeKind::Gitlab => "gitlab",
+                ForgeKind::Github => "github",
+                ForgeKind::Gitea => "gitea",
+            }
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HookError {
+    #[error("missing secret token")]
+    MissingSecret,
+    #[error("incorrect secret token")]
+    IncorrectSecret,
+    #[error("cannot decode event")]
+    Decode(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum State {
+    Running,
+    Success,
+    Failed,
+    /// The tests could not be run because of a grader error
+    Canceled,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                State::Running => "running",
+                State::Success => "success",
+                State::Failed => "failed",
+                State::Canceled => "canceled",
+            }
+        )
+    }
+}
+
+/// Operations needed from the hosting service of the student repositories
+pub trait Forge: Send + Sync {
+    /// Check that a webhook delivery comes from the forge, if a secret is configured
+    fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), HookError>;
+
+    /// Decode a webhook delivery. Events which are not handled, such as pings,
+    /// give `None`.
+    fn parse_hook(&self, headers: &HeaderMap, body: &[u8]) -> Result<Option<Hook>, HookError>;
+
+    /// Credentials used to clone repositories and to authenticate API
+    /// requests, which are added when the requests are sent
+    fn credentials(&self) -> eyre::Result<Credentials>;
+
+    fn post_status(
+        &self,
+        hook: &Hook,
+        state: &State,
+        name: &str,
+        description: Option<&str>,
+    ) -> Request<String>;
+
+    /// Post `note` on the merge request for merge request events, or on the
+    /// pushed commit otherwise. Forges which cannot comment on commits
+    /// return `None` for pushes.
+    fn post_comment(&self, hook: &Hook, note: &str) -> Option<Request<String>>;
+}
+
+/// Return the forge of the given kind, if it is configured
+pub fn forge(config: &Configuration, kind: ForgeKind) -> Option<Box<dyn Forge + '_>> {
+    match kind {
+        ForgeKind::Gitlab => config
+            .gitlab
+            .as_ref()
+            .map(|config| Box::new(gitlab::Gitlab(config)) as Box<dyn Forge>),
+        ForgeKind::Github => config
+            .github
+            .as_ref()
+            .map(|config| Box::new(github::Github(config)) as Box<dyn Forge>),
+        ForgeKind::Gitea => config
+            .gitea
+            .as_ref()
+            .map(|config| Box::new(gitea::Gitea(config)) as Box<dyn Forge>),
+    }
+}
+
+/// Credentials of every configured forge
+pub fn credentials(config: &Configuration) -> eyre::Result<HashMap<ForgeKind, Credentials>> {
+    [ForgeKind::Gitlab, ForgeKind::Github, ForgeKind::Gitea]
+        .into_iter()
+        .filter_map(|kind| {
+            let forge = forge(config, kind)?;
+            Some(forge.credentials().map(|credentials| (kind, credentials)))
+        })
+        .collect()
+}
+
+pub enum Token {
+    /// Token given in the configuration file
+    Static(String),
+    /// Installation token of a GitHub App, refreshed before it expires
+    GithubApp(github::AppTokens),
+}
+
+pub struct Credentials {
+    /// Header carrying the token, and prefix of the token in this header
+    pub header: (HeaderName, &'static str),
+    /// Username used with the token to clone repositories
+    pub username: String,
+    pub token: Token,
+}
+
+impl Credentials {
+    pub async fn token(&self, client: &HttpClient) -> eyre::Result<String> {
+        match self.token {
+            Token::Static(ref token) => Ok(token.clone()),
+            Token::GithubApp(ref app) => app.token(client).await,
+        }
+    }
+
+    /// Header authenticating API requests
+    pub async fn authorization(
+        &self,
+        client: &HttpClient,
+    ) -> eyre::Result<(HeaderName, HeaderValue)> {
+        let (ref name, prefix) = self.header;
+        let token = self.token(client).await?;
+        Ok((
+            name.clone(),
+            HeaderValue::from_str(&format!("{prefix}{token}"))?,
+        ))
+    }
+}
+
+/// Push event, or merge request event converted into a push of the
+/// merge request source branch
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hook {
+    /// Events queued before the support of several forges come from GitLab
+    #[serde(default)]
+    pub forge: ForgeKind,
+    pub object_kind: String,
+    checkout_sha: Option<String>,
+    /// Project identifier in the forge API: a number for GitLab,
+    /// `owner/name` for other forges
+    #[serde(deserialize_with = "string_or_number")]
+    project_id: String,
+    #[serde(rename = "ref")]
+    pub ref_: String,
+    pub repository: Repository,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge_request: Option<MergeRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submission: Option<Submission>,
+    /// Reception time, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Repository {
+    pub name: String,
+    pub homepage: Url,
+    pub git_http_url: Url,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MergeRequest {
+    iid: u32,
+    #[serde(deserialize_with = "string_or_number")]
+    target_project_id: String,
+    /// Absent from the events queued before it was recorded
+    #[serde(default)]
+    target_branch: String,
+    /// Such as `open`, `reopen`, `update` or `merge`
+    action: Option<String>,
+    /// The update brought new commits
+    new_commits: bool,
+}
+
+/// Official submission of a lab, made by pushing a tag
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Submission {
+    pub lab: String,
+    /// Reception time, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(u64),
+        String(String),
+    }
+    Ok(match Id::deserialize(deserializer)? {
+        Id::Number(n) => n.to_string(),
+        Id::String(s) => s,
+    })
+}
+
+impl Hook {
+    /// Check if the event brings new code to grade
+    pub fn is_gradable(&self) -> bool {
+        match (self.object_kind.as_str(), &self.merge_request) {
+            ("push", _) => !self.is_delete(),
+            ("tag_push", _) => !self.is_delete() && self.submission.is_some(),
+            ("merge_request", Some(mr)) => match mr.action.as_deref() {
+                Some("open" | "reopen") => true,
+                Some("update") => mr.new_commits,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn is_delete(&self) -> bool {
+        self.checkout_sha.is_none()
+    }
+
+    pub fn pushed_sha(&self) -> &str {
+        match self.checkout_sha {
+            Some(ref s) => s,
+            None => panic!(),
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.repository.git_http_url
+    }
+
+    pub fn branch_name(&self) -> Option<&str> {
+        if self.ref_.starts_with("refs/heads/") {
+            Some(&self.ref_[11..])
+        } else {
+            None
+        }
+    }
+
+    pub fn tag_name(&self) -> Option<&str> {
+        self.ref_.strip_prefix("refs/tags/")
+    }
+
+    /// Branch or tag name
+    pub fn ref_name(&self) -> Option<&str> {
+        self.branch_name().or_else(|| self.tag_name())
+    }
+
+    pub fn short_ref(&self) -> &str {
+        self.ref_name().unwrap_or(&self.ref_)
+    }
+
+    pub fn repository_url(&self) -> &Url {
+        &self.repository.homepage
+    }
+
+    /// Branch into which the merge request would be merged
+    pub fn target_branch(&self) -> Option<&str> {
+        self.merge_request
+            .as_ref()
+            .map(|mr| mr.target_branch.as_str())
+    }
+
+    /// Reception time of the event, used for deadlines
+    pub fn received_at(&self) -> u64 {
+        self.received_at.unwrap_or_else(unix_time)
+    }
+
+    /// Mark this event as the official submission of `lab`
+    pub fn mark_as_submission(&mut self, lab: String) {
+        self.submission = Some(Submission {
+            lab,
+            timestamp: self.received_at(),
+        });
+    }
+
+    /// Name of the commit status for `lab`, distinct for official submissions
+    pub fn status_name(&self, lab: &str) -> String {
+        if self.submission.is_some() {
+            format!("{lab} submission")
+        } else {
+            lab.to_owned()
+        }
+    }
+
+    /// Key shared by the requests setting the status `name` of the pushed
+    /// commit in this project, the newest of which supersedes the others.
+    /// Forks of the same template share commits, hence the project.
+    pub fn status_key(&self, name: &str) -> String {
+        format!("{}/{}/{name}", self.project_id, self.pushed_sha())
+    }
+
+    pub fn desc(&self) -> String {
+        format!(
+            "{} ({}{} - {})",
+            self.repository.name,
+            self.merge_request.as_ref().map_or_else(String::new, |mr| {
+                let mark = if self.forge == ForgeKind::Gitlab {
+                    '!'
+                } else {
+                    '#'
+                };
+                format!("{mark}{} ", mr.iid)
+            }),
+            self.short_ref(),
+            match self.checkout_sha {
+                Some(ref s) => &s[..8],
+                None => "<deleted>",
+            }
+        )
+    }
+}
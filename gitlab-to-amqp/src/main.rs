@@ -1,28 +1,86 @@
 mod amqp;
+mod client;
 mod config;
-mod gitlab;
+mod forge;
+mod package;
 mod poster;
 mod report;
+mod store;
 mod web;
 
 use clap::{arg, command};
+use client::HttpClient;
 use config::Configuration;
 use futures::channel::mpsc;
-use futures::{StreamExt, TryFutureExt, TryStreamExt, stream, try_join};
+use futures::{FutureExt, StreamExt, TryFutureExt, TryStreamExt, stream, try_join};
+use poster::Poster;
 use std::sync::Arc;
+use store::Store;
 use tokio::sync::Semaphore;
 
-fn configuration() -> eyre::Result<Configuration> {
+/// Name used to identify the messages produced by this program
+static PRODUCER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+fn open_store(config: &Configuration) -> eyre::Result<Option<Store>> {
+    Ok(config
+        .store
+        .as_ref()
+        .map(|store| Store::open(&store.path))
+        .transpose()?)
+}
+
+/// Print the best and latest grades of every lab of `repository`, or the
+/// history of `lab` if given
+fn print_grades(config: &Configuration, repository: &str, lab: Option<&str>) -> eyre::Result<()> {
+    let Some(store) = open_store(config)? else {
+        eyre::bail!("no store configured");
+    };
+    if let Some(lab) = lab {
+        for record in store.history(repository, lab)? {
+            println!("{}", serde_json::to_string(&record)?);
+        }
+        return Ok(());
+    }
+    for lab in store.labs(repository)? {
+        let show = |record: Option<store::GradeRecord>| {
+            record.map_or_else(
+                || String::from("-"),
+                |record| {
+                    format!(
+                        "{:.2}/{} ({})",
+                        record.effective_grade(),
+                        record.max_grade,
+                        &record.sha[..record.sha.len().min(8)]
+                    )
+                },
+            )
+        };
+        println!(
+            "{lab}: best {}, latest {}",
+            show(store.best(repository, &lab)?),
+            show(store.latest(repository, &lab)?)
+        );
+    }
+    Ok(())
+}
+
+async fn run() -> eyre::Result<()> {
     let matches = command!()
         .arg(arg!(-c --config <FILE> "Configuration file containing credentials").required(true))
+        .arg(arg!(--grades <REPOSITORY> "Print the stored grades of a repository and exit"))
+        .arg(arg!(--lab <LAB> "With --grades, print the history of this lab").requires("grades"))
         .get_matches();
     let config = config::load_configuration(matches.get_one::<String>("config").unwrap())?;
+    if let Some(repository) = matches.get_one::<String>("grades") {
+        return print_grades(
+            &config,
+            repository,
+            matches.get_one::<String>("lab").map(String::as_str),
+        );
+    }
     config::setup_dirs(&config)?;
-    Ok(config)
-}
-
-async fn run() -> eyre::Result<()> {
-    let config = Arc::new(configuration()?);
+    let config = Arc::new(config);
+    let store = open_store(&config)?.map(Arc::new);
     log::info!(
         "configured for labs {:?}",
         config
@@ -33,23 +91,31 @@ async fn run() -> eyre::Result<()> {
             .collect::<Vec<_>>()
     );
     let cpu_access = Semaphore::new(config.package.threads);
+    let client = HttpClient::new(&config.http)?;
+    let poster = Arc::new(Poster::new(
+        &config.poster,
+        client,
+        forge::credentials(&config)?,
+    ));
     let (send_hook, receive_hook) = mpsc::channel(16);
     let (send_request, receive_request) = mpsc::channel(16);
     let (send_response, receive_response) = mpsc::channel(16);
-    let packager = gitlab::packager(&config, &cpu_access, receive_hook, send_request);
+    let packager = package::packager(&config, &cpu_access, &poster, receive_hook, send_request);
     let amqp_process =
         amqp::amqp_process(&config, receive_request, send_response).map_err(Into::into);
     let response_poster = receive_response
         .map(Ok)
         .try_for_each_concurrent(None, |response| {
             let cloned_config = config.clone();
+            let store = store.clone();
+            let poster = poster.clone();
             async move {
                 log::trace!("Received reponse: {response:?}");
-                match report::response_to_post(&cloned_config, &response) {
-                    Ok(rqs) => {
+                match report::response_to_post(&cloned_config, store.as_deref(), &response) {
+                    Ok((forge, rqs)) => {
                         stream::iter(rqs)
-                            .for_each_concurrent(None, |rq| async {
-                                if let Err(e) = poster::post(rq).await {
+                            .for_each_concurrent(None, |(status, rq)| async {
+                                if let Err(e) = poster.post(forge, status, rq).await {
                                     log::warn!("unable to post response: {e}");
                                 }
                             })
@@ -64,7 +130,8 @@ async fn run() -> eyre::Result<()> {
             }
         });
     let web_server = web::web_server(&config, send_hook);
-    try_join!(packager, amqp_process, response_poster, web_server)?;
+    let outbox = poster.flush_periodically().map(Ok);
+    try_join!(packager, amqp_process, response_poster, web_server, outbox)?;
     Ok(())
 }
 
@@ -1,99 +1,33 @@
-#![allow(clippy::module_name_repetitions)]
-
-pub mod api;
-
-use self::api::State;
 use amqp_utils::AmqpRequest;
 use futures::channel::mpsc::{Receiver, Sender};
 use futures::{SinkExt, Stream, StreamExt, future, stream};
 use git2::build::{CheckoutBuilder, RepoBuilder};
 use git2::{Cred, FetchOptions, RemoteCallbacks, Repository};
 use graders_utils::ziputils::zip_recursive;
-use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
 use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
 use url::Url;
 use uuid::Uuid;
 
-use crate::config::Configuration;
-use crate::poster;
+use crate::config::{Configuration, Timeliness};
+use crate::forge::{self, Forge, Hook, State};
+use crate::poster::Poster;
 
-static GITLAB_USERNAME: &str = "grader";
 pub static RESULT_QUEUE: &str = "gitlab";
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct GitlabHook {
-    pub object_kind: String,
-    checkout_sha: Option<String>,
-    project_id: u32,
-    #[serde(rename = "ref")]
-    pub ref_: String,
-    pub repository: GitlabRepository,
-}
-
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct GitlabRepository {
-    name: String,
-    homepage: Url,
-    git_http_url: Url,
-}
-
-impl GitlabHook {
-    pub fn is_delete(&self) -> bool {
-        self.checkout_sha.is_none()
-    }
-
-    pub fn pushed_sha(&self) -> &str {
-        match self.checkout_sha {
-            Some(ref s) => s,
-            None => panic!(),
-        }
-    }
-
-    pub fn url(&self) -> &Url {
-        &self.repository.git_http_url
-    }
-
-    pub fn branch_name(&self) -> Option<&str> {
-        if self.ref_.starts_with("refs/heads/") {
-            Some(&self.ref_[11..])
-        } else {
-            None
-        }
-    }
-
-    pub fn short_ref(&self) -> &str {
-        self.branch_name().unwrap_or(&self.ref_)
-    }
-
-    pub fn desc(&self) -> String {
-        format!(
-            "{} ({} - {})",
-            self.repository.name,
-            self.short_ref(),
-            match self.checkout_sha {
-                Some(ref s) => &s[..8],
-                None => "<deleted>",
-            }
-        )
-    }
-}
-
-fn clone(token: &str, hook: &GitlabHook, dir: &Path) -> eyre::Result<Repository> {
-    let token_for_clone = token.to_owned();
+fn clone(username: &str, token: &str, hook: &Hook, dir: &Path) -> eyre::Result<Repository> {
     let mut callbacks = RemoteCallbacks::new();
-    callbacks
-        .credentials(move |_, _, _| Cred::userpass_plaintext(GITLAB_USERNAME, &token_for_clone));
+    callbacks.credentials(move |_, _, _| Cred::userpass_plaintext(username, token));
     let mut fetch_options = FetchOptions::new();
     fetch_options.remote_callbacks(callbacks);
     log::trace!(
         "cloning {:?} into {:?} with username {} and token {}",
         hook.url(),
         dir,
-        GITLAB_USERNAME,
+        username,
         token
     );
     let repo = RepoBuilder::new()
@@ -120,39 +54,87 @@ fn clone(token: &str, hook: &GitlabHook, dir: &Path) -> eyre::Result<Repository>
     Ok(repo)
 }
 
+/// Post the status `name` without waiting, so that retries do not delay
+/// packaging
+fn post_in_background(
+    poster: &Arc<Poster>,
+    forge: &dyn Forge,
+    hook: &Hook,
+    state: &State,
+    name: &str,
+    description: &str,
+) {
+    let post = poster.post(
+        hook.forge,
+        Some(hook.status_key(name)),
+        forge.post_status(hook, state, name, Some(description)),
+    );
+    let state = format!("{state}");
+    tokio::spawn(async move {
+        if let Err(e) = post.await {
+            log::warn!("unable to post {state} status: {e}");
+        }
+    });
+}
+
 /// Clone and package labs to test. Return a list of (lab, zip base name).
 /// This will use a blocking threadpool for the zip operation itself.
 async fn package(
     config: &Configuration,
-    hook: &GitlabHook,
+    poster: &Arc<Poster>,
+    hook: &Hook,
 ) -> eyre::Result<Vec<(String, String, String)>> {
     log::info!("packaging {}", hook.desc());
+    let forge = forge::forge(config, hook.forge)
+        .ok_or_else(|| eyre::eyre!("{} is not configured", hook.forge))?;
     let temp = tempfile::TempDir::new()?;
     let root = temp.keep();
-    let _repo = clone(&config.gitlab.token, hook, &root).map_err(|e| {
+    let (username, token) = poster.clone_credentials(hook.forge).await?;
+    let _repo = clone(&username, &token, hook, &root).map_err(|e| {
         log::error!("error when cloning: {e}");
         e
     })?;
     let zip_dir = &Path::new(&config.package.zip_dir);
     let mut to_test = Vec::new();
-    for lab in config.labs.iter().filter(|l| l.is_enabled()).cloned() {
+    let labs = config.labs.iter().filter(|l| {
+        l.is_enabled()
+            && hook
+                .submission
+                .as_ref()
+                .is_none_or(|submission| submission.lab == l.name)
+    });
+    for lab in labs.cloned() {
         let path = root.join(&lab.base).join(&lab.dir);
         log::trace!("looking for witness {:?} in path {:?}", lab.witness, path);
         if path.is_dir() && lab.witness.clone().is_none_or(|w| path.join(w).is_file()) {
+            match lab.timeliness(hook.received_at()) {
+                Timeliness::NotOpen => {
+                    log::info!("lab {} is not open yet for {}", lab.name, hook.desc());
+                    continue;
+                }
+                Timeliness::Refused => {
+                    log::info!("deadline passed for lab {} for {}", lab.name, hook.desc());
+                    post_in_background(
+                        poster,
+                        forge.as_ref(),
+                        hook,
+                        &State::Failed,
+                        &hook.status_name(&lab.name),
+                        "deadline passed",
+                    );
+                    continue;
+                }
+                Timeliness::OnTime | Timeliness::Late { .. } => (),
+            }
             log::trace!("publishing initial {} status for {}", lab.name, hook.desc());
-            match poster::post(api::post_status(
-                &config.gitlab,
+            post_in_background(
+                poster,
+                forge.as_ref(),
                 hook,
                 &State::Running,
-                hook.branch_name(),
-                &lab.name,
-                Some("packaging and testing"),
-            ))
-            .await
-            {
-                Ok(_) => (),
-                Err(e) => log::warn!("unable to post initial status: {e}"),
-            };
+                &hook.status_name(&lab.name),
+                "packaging and testing",
+            );
             log::trace!("packaging lab {} from {:?}", lab.name, path);
             let zip_basename = format!("{}.zip", Uuid::new_v4());
             let zip_file = zip_dir.join(&zip_basename);
@@ -161,26 +143,27 @@ async fn package(
                 tokio::task::spawn_blocking(move || zip_recursive(&path, &lab_dir, &zip_file))
                     .await?;
             match zipped {
-                Ok(_) => to_test.push((
-                    lab.name.clone(),
-                    lab.dir.to_string_lossy().to_string(),
-                    zip_basename,
-                )),
+                Ok(_) => {
+                    // Only submissions which are actually graded are recorded
+                    if let Err(e) = record_submission(config, hook) {
+                        log::error!("cannot record submission: {e}");
+                    }
+                    to_test.push((
+                        lab.name.clone(),
+                        lab.dir.to_string_lossy().to_string(),
+                        zip_basename,
+                    ));
+                }
                 Err(e) => {
                     log::error!("cannot package {:?} (lab {}): {}", hook.url(), lab.name, e);
-                    match poster::post(api::post_status(
-                        &config.gitlab,
+                    post_in_background(
+                        poster,
+                        forge.as_ref(),
                         hook,
                         &State::Failed,
-                        hook.branch_name(),
-                        &lab.name,
-                        Some("unable to package compiler"),
-                    ))
-                    .await
-                    {
-                        Ok(_) => (),
-                        Err(e) => log::warn!("unable to post packaging error status: {e}"),
-                    }
+                        &hook.status_name(&lab.name),
+                        "unable to package compiler",
+                    );
                 }
             }
         }
@@ -191,7 +174,7 @@ async fn package(
 
 fn labs_result_to_stream(
     base_url: &Url,
-    hook: &GitlabHook,
+    hook: &Hook,
     labs: Vec<(String, String, String)>,
 ) -> impl Stream<Item = AmqpRequest> + use<> {
     let hook = hook.clone();
@@ -199,7 +182,8 @@ fn labs_result_to_stream(
     stream::iter(labs.into_iter().map(move |(lab, dir, zip)| {
         AmqpRequest {
             job_name: format!(
-                "[gitlab:{}:{}:{}:{}:{}]",
+                "[{}:{}:{}:{}:{}:{}]",
+                hook.forge,
                 &hook.repository.name,
                 &hook.repository.homepage,
                 &hook.ref_,
@@ -224,17 +208,18 @@ fn labs_result_to_stream(
 pub async fn packager(
     config: &Arc<Configuration>,
     cpu_access: &Semaphore,
-    receive_hook: Receiver<GitlabHook>,
+    poster: &Arc<Poster>,
+    receive_hook: Receiver<Hook>,
     send_request: Sender<AmqpRequest>,
 ) -> eyre::Result<()> {
     let labs = receive_hook
-        .then(move |hook: GitlabHook| {
+        .then(move |hook: Hook| {
             let config = config.clone();
             async move {
                 let clone_hook = hook.clone();
                 let base_url = config.server.base_url.clone();
                 let _permit = cpu_access.acquire().await;
-                let labs = package(&config, &clone_hook).await?;
+                let labs = package(&config, poster, &clone_hook).await?;
                 Ok::<_, eyre::Report>(labs_result_to_stream(&base_url, &hook, labs))
             }
         })
@@ -247,14 +232,36 @@ pub async fn packager(
         .await
 }
 
+/// Append the submission to the submissions file, if any, as a JSON line
+fn record_submission(config: &Configuration, hook: &Hook) -> io::Result<()> {
+    let (Some(submissions_file), Some(submission)) =
+        (&config.grading.submissions_file, &hook.submission)
+    else {
+        return Ok(());
+    };
+    let record = serde_json::json!({
+        "repository": hook.repository.name,
+        "homepage": hook.repository.homepage,
+        "tag": hook.short_ref(),
+        "sha": hook.pushed_sha(),
+        "lab": submission.lab,
+        "timestamp": submission.timestamp,
+    });
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(submissions_file)?;
+    writeln!(file, "{record}")
+}
+
 pub fn remove_zip_file(config: &Configuration, zip: &str) -> io::Result<()> {
     fs::remove_file(config.package.zip_dir.join(zip))
 }
 
-pub fn to_opaque(hook: &GitlabHook, zip_file_name: &str) -> String {
+pub fn to_opaque(hook: &Hook, zip_file_name: &str) -> String {
     serde_json::to_string(&(hook, zip_file_name)).unwrap()
 }
 
-pub fn from_opaque(opaque: &str) -> serde_json::Result<(GitlabHook, String)> {
+pub fn from_opaque(opaque: &str) -> serde_json::Result<(Hook, String)> {
     serde_json::from_str(opaque)
 }
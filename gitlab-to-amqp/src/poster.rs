@@ -1,32 +1,716 @@
+use graders_utils::time::{since_epoch, unix_time};
 use http_body_util::Full;
 use hyper::body::Bytes;
+use hyper::header::{
+    AUTHORIZATION, HeaderMap, HeaderName, HeaderValue, PROXY_AUTHORIZATION, RETRY_AFTER,
+};
 use hyper::{Request, StatusCode};
-use hyper_tls::HttpsConnector;
-use hyper_util::client::legacy::Client;
-use hyper_util::rt::TokioExecutor;
-
-pub async fn post(
-    request: Request<String>,
-) -> Result<StatusCode, Box<dyn std::error::Error + Send + Sync>> {
-    let https = HttpsConnector::new();
-    let client: Client<_, Full<Bytes>> = Client::builder(TokioExecutor::new()).build(https);
-    log::trace!(
-        "preparing to post request to {} ({})",
-        request.uri(),
-        request.method()
-    );
-    let request = request.map(|body| Full::new(Bytes::from(body)));
-    let uri = request.uri().clone();
-    let method = request.method().clone();
-    let result = client
-        .request(request)
-        .await
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-    log::trace!(
-        "request to {} ({}) returned {}",
-        uri,
-        method,
-        result.status()
-    );
-    Ok(result.status())
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use uuid::Uuid;
+
+use crate::client::{ClientError, HttpClient};
+use crate::config::PosterConfiguration;
+use crate::forge::{Credentials, ForgeKind};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PostError {
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error("invalid request: {0}")]
+    Invalid(#[from] hyper::http::Error),
+    #[error("cannot obtain credentials: {0}")]
+    Credentials(eyre::Report),
+    /// Error status, with the delay requested by the server before retrying
+    #[error("request rejected with status {0}")]
+    Status(StatusCode, Option<Duration>),
+    #[error("superseded by a newer status")]
+    Superseded,
+}
+
+impl PostError {
+    /// Check if the request may succeed later
+    fn is_transient(&self) -> bool {
+        match self {
+            PostError::Client(_) | PostError::Credentials(_) => true,
+            PostError::Invalid(_) | PostError::Superseded => false,
+            PostError::Status(status, retry_after) => {
+                matches!(
+                    *status,
+                    StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                ) || status.is_server_error()
+                    // GitHub signals an exhausted rate limit with 403
+                    || (*status == StatusCode::FORBIDDEN && retry_after.is_some())
+            }
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            PostError::Status(_, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Headers which are never written to the outbox
+static CREDENTIAL_HEADERS: [HeaderName; 3] = [
+    AUTHORIZATION,
+    PROXY_AUTHORIZATION,
+    HeaderName::from_static("private-token"),
+];
+
+/// Request kept in the outbox until it has been delivered. Credentials are
+/// not kept, the current ones are added when the request is sent.
+#[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
+struct Pending {
+    #[serde(default)]
+    forge: ForgeKind,
+    /// Status set by the request, which newer requests setting the same
+    /// status supersede
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Pending {
+    fn new(forge: ForgeKind, status: Option<String>, request: Request<String>) -> Pending {
+        let (parts, body) = request.into_parts();
+        Pending {
+            forge,
+            status,
+            method: parts.method.to_string(),
+            uri: parts.uri.to_string(),
+            headers: parts
+                .headers
+                .iter()
+                .filter(|(name, _)| !CREDENTIAL_HEADERS.contains(name))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            body,
+        }
+    }
+
+    fn to_request(
+        &self,
+        authorization: Option<&(HeaderName, HeaderValue)>,
+    ) -> Result<Request<Full<Bytes>>, hyper::http::Error> {
+        let mut builder = Request::builder()
+            .method(self.method.as_str())
+            .uri(&self.uri);
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some((name, value)) = authorization {
+            builder = builder.header(name, value);
+        }
+        builder.body(Full::new(Bytes::from(self.body.clone())))
+    }
+}
+
+/// Delay requested by the server through `Retry-After`, or through the
+/// rate limit headers of GitHub
+fn retry_after(headers: &HeaderMap, now: u64) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|h| h.to_str().ok());
+    let until = |timestamp: i64| {
+        Duration::from_secs(u64::try_from(timestamp).map_or(0, |t| t.saturating_sub(now)))
+    };
+    if let Some(retry_after) = header(RETRY_AFTER.as_str()) {
+        return retry_after
+            .parse()
+            .map(Duration::from_secs)
+            .ok()
+            .or_else(|| {
+                chrono::DateTime::parse_from_rfc2822(retry_after)
+                    .ok()
+                    .map(|date| until(date.timestamp()))
+            });
+    }
+    if header("x-ratelimit-remaining") == Some("0") {
+        return header("x-ratelimit-reset")
+            .and_then(|reset| reset.parse().ok())
+            .map(until);
+    }
+    None
+}
+
+/// Newest request setting a status
+struct Latest {
+    id: u64,
+    /// Shared by the successive requests setting the status, so that they
+    /// are never sent concurrently
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Request being delivered, or waiting in the outbox for the next flush
+struct Entry {
+    id: u64,
+    pending: Pending,
+    path: Option<PathBuf>,
+    lock: Option<Arc<tokio::sync::Mutex<()>>>,
+}
+
+/// Deliver requests to the forges, retrying with an exponential backoff
+pub struct Poster {
+    config: PosterConfiguration,
+    client: HttpClient,
+    credentials: HashMap<ForgeKind, Credentials>,
+    next_id: AtomicU64,
+    /// Newest pending request of every status, by forge and status key
+    latest: Mutex<HashMap<(ForgeKind, String), Latest>>,
+    /// Requests of the outbox waiting for the next flush, in creation order
+    stalled: Mutex<BTreeMap<PathBuf, Entry>>,
+}
+
+impl Poster {
+    /// Create a poster, and queue the requests left in the outbox by a
+    /// previous run before any new one
+    pub fn new(
+        config: &PosterConfiguration,
+        client: HttpClient,
+        credentials: HashMap<ForgeKind, Credentials>,
+    ) -> Poster {
+        let poster = Poster {
+            config: config.clone(),
+            client,
+            credentials,
+            next_id: AtomicU64::new(0),
+            latest: Mutex::new(HashMap::new()),
+            stalled: Mutex::new(BTreeMap::new()),
+        };
+        poster.load();
+        poster
+    }
+
+    /// Post `request`, which is kept in the outbox, if any, until it has
+    /// been delivered or rejected. A request setting a `status` (see
+    /// [`Hook::status_key`](crate::forge::Hook::status_key)) supersedes the
+    /// pending ones setting the same status, which are dropped.
+    pub fn post(
+        self: &Arc<Self>,
+        forge: ForgeKind,
+        status: Option<String>,
+        request: Request<String>,
+    ) -> impl Future<Output = Result<StatusCode, PostError>> + Send + use<> {
+        let pending = Pending::new(forge, status, request);
+        let path = self.save(&pending).unwrap_or_else(|e| {
+            log::warn!("cannot save request to {} in outbox: {e}", pending.uri);
+            None
+        });
+        let entry = self.register(pending, path);
+        let poster = self.clone();
+        async move { poster.deliver(entry, poster.config.max_attempts).await }
+    }
+
+    /// Username and token used to clone the repositories of `forge`
+    pub async fn clone_credentials(&self, forge: ForgeKind) -> eyre::Result<(String, String)> {
+        let credentials = self
+            .credentials
+            .get(&forge)
+            .ok_or_else(|| eyre::eyre!("{forge} is not configured"))?;
+        Ok((
+            credentials.username.clone(),
+            credentials.token(&self.client).await?,
+        ))
+    }
+
+    /// Post the requests of the outbox every `flush_interval` seconds
+    pub async fn flush_periodically(&self) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.flush_interval.max(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Post the requests of the outbox one at a time, in order, and stop
+    /// at the first one which still cannot be delivered
+    async fn flush(&self) {
+        let stalled = std::mem::take(&mut *self.stalled.lock().unwrap());
+        if stalled.is_empty() {
+            return;
+        }
+        log::info!("posting {} requests from the outbox", stalled.len());
+        let mut entries = stalled.into_values();
+        while let Some(entry) = entries.next() {
+            match self.deliver(entry, 1).await {
+                Ok(_) | Err(PostError::Superseded) => (),
+                Err(e) if e.is_transient() => {
+                    log::warn!("unable to post request from outbox, will retry later: {e}");
+                    self.stalled
+                        .lock()
+                        .unwrap()
+                        .extend(entries.filter_map(|entry| Some((entry.path.clone()?, entry))));
+                    return;
+                }
+                Err(e) => log::warn!("unable to post request from outbox: {e}"),
+            }
+        }
+    }
+
+    /// Queue the requests left in the outbox by a previous run
+    fn load(&self) {
+        let Some(outbox) = &self.config.outbox else {
+            return;
+        };
+        let mut paths = match fs::read_dir(outbox) {
+            Ok(entries) => entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                log::error!("cannot read outbox {outbox:?}: {e}");
+                return;
+            }
+        };
+        paths.sort();
+        if !paths.is_empty() {
+            log::info!("{} requests left in the outbox", paths.len());
+        }
+        for path in paths {
+            let pending = match fs::read(&path)
+                .map_err(eyre::Report::new)
+                .and_then(|content| Ok(serde_json::from_slice::<Pending>(&content)?))
+            {
+                Ok(pending) => pending,
+                Err(e) => {
+                    log::error!("removing unreadable outbox entry {path:?}: {e}");
+                    remove(&path);
+                    continue;
+                }
+            };
+            let entry = self.register(pending, Some(path.clone()));
+            self.stalled.lock().unwrap().insert(path, entry);
+        }
+    }
+
+    /// Make `pending` the newest request setting its status, if any
+    fn register(&self, pending: Pending, path: Option<PathBuf>) -> Entry {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let lock = pending.status.as_ref().map(|status| {
+            let mut latest = self.latest.lock().unwrap();
+            let latest = latest
+                .entry((pending.forge, status.clone()))
+                .or_insert_with(|| Latest {
+                    id,
+                    lock: Arc::default(),
+                });
+            latest.id = id;
+            latest.lock.clone()
+        });
+        Entry {
+            id,
+            pending,
+            path,
+            lock,
+        }
+    }
+
+    /// Check if a newer request setting the same status has been registered.
+    /// The entry of the newest one is removed once it has been delivered.
+    fn is_superseded(&self, entry: &Entry) -> bool {
+        entry.pending.status.as_ref().is_some_and(|status| {
+            self.latest
+                .lock()
+                .unwrap()
+                .get(&(entry.pending.forge, status.clone()))
+                .is_none_or(|latest| latest.id != entry.id)
+        })
+    }
+
+    /// Forget about the status set by `entry` if it is the newest one
+    fn release(&self, entry: &Entry) {
+        if let Some(status) = &entry.pending.status {
+            let key = (entry.pending.forge, status.clone());
+            let mut latest = self.latest.lock().unwrap();
+            if latest.get(&key).is_some_and(|latest| latest.id == entry.id) {
+                latest.remove(&key);
+            }
+        }
+    }
+
+    async fn send(&self, pending: &Pending) -> Result<StatusCode, PostError> {
+        log::trace!(
+            "preparing to post request to {} ({})",
+            pending.uri,
+            pending.method
+        );
+        let authorization = match self.credentials.get(&pending.forge) {
+            Some(credentials) => Some(
+                credentials
+                    .authorization(&self.client)
+                    .await
+                    .map_err(PostError::Credentials)?,
+            ),
+            None => None,
+        };
+        let request = pending.to_request(authorization.as_ref())?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        log::trace!(
+            "request to {} ({}) returned {}",
+            pending.uri,
+            pending.method,
+            status
+        );
+        if status.is_client_error() || status.is_server_error() {
+            return Err(PostError::Status(
+                status,
+                retry_after(response.headers(), unix_time()),
+            ));
+        }
+        Ok(status)
+    }
+
+    fn save(&self, pending: &Pending) -> io::Result<Option<PathBuf>> {
+        let Some(outbox) = &self.config.outbox else {
+            return Ok(None);
+        };
+        // Names sort by creation time so that the outbox is resent in order
+        let now = since_epoch().as_millis();
+        let path = outbox.join(format!("{now:015}-{}.json", Uuid::new_v4()));
+        let mut file = tempfile::NamedTempFile::new_in(outbox)?;
+        file.write_all(&serde_json::to_vec(pending)?)?;
+        file.persist(&path)?;
+        Ok(Some(path))
+    }
+
+    async fn deliver(&self, entry: Entry, max_attempts: u32) -> Result<StatusCode, PostError> {
+        let max_delay = Duration::from_secs(self.config.max_delay);
+        let mut attempt = 1;
+        let result = loop {
+            let result = {
+                let _guard = match entry.lock {
+                    Some(ref lock) => Some(lock.lock().await),
+                    None => None,
+                };
+                if self.is_superseded(&entry) {
+                    log::debug!(
+                        "dropping request to {} superseded by a newer status",
+                        entry.pending.uri
+                    );
+                    break Err(PostError::Superseded);
+                }
+                self.send(&entry.pending).await
+            };
+            match result {
+                Err(e) if e.is_transient() && attempt < max_attempts => {
+                    let delay = e
+                        .retry_after()
+                        .map_or_else(|| self.config.delay(attempt), |delay| delay.min(max_delay));
+                    log::info!(
+                        "retrying request to {} in {delay:?} (attempt {attempt}): {e}",
+                        entry.pending.uri
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        let transient = result.as_ref().is_err_and(PostError::is_transient);
+        match entry.path.clone() {
+            // Requests which failed transiently stay in the outbox until the next flush
+            Some(path) if transient => {
+                self.stalled.lock().unwrap().insert(path, entry);
+            }
+            path => {
+                if let Some(path) = path {
+                    remove(&path);
+                }
+                self.release(&entry);
+            }
+        }
+        result
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        log::warn!("cannot remove outbox entry {path:?}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfiguration;
+    use crate::forge::{Hook, Token};
+    use hyper::Response;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    /// Server answering with the given statuses in turn, then with 201, and
+    /// rejecting requests without the current token
+    async fn mock_forge(statuses: &'static [u16], hits: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let hits = hits.clone();
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let hit = hits.fetch_add(1, Ordering::SeqCst);
+                    let status = if request
+                        .headers()
+                        .get("private-token")
+                        .is_some_and(|token| token == "current")
+                    {
+                        statuses.get(hit).copied().unwrap_or(201)
+                    } else {
+                        401
+                    };
+                    async move {
+                        Response::builder()
+                            .status(status)
+                            .header(RETRY_AFTER, "0")
+                            .body(Full::new(Bytes::new()))
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        url
+    }
+
+    fn poster(outbox: &Path, token: &'static str) -> Arc<Poster> {
+        Arc::new(Poster::new(
+            &PosterConfiguration {
+                initial_delay: 0,
+                max_attempts: 3,
+                outbox: Some(outbox.to_owned()),
+                ..PosterConfiguration::default()
+            },
+            HttpClient::new(&HttpConfiguration::default()).unwrap(),
+            HashMap::from([(
+                ForgeKind::Gitlab,
+                Credentials {
+                    header: (HeaderName::from_static("private-token"), ""),
+                    username: String::from("grader"),
+                    token: Token::Static(token.to_owned()),
+                },
+            )]),
+        ))
+    }
+
+    fn outbox_len(outbox: &Path) -> usize {
+        fs::read_dir(outbox).unwrap().count()
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            pairs
+                .iter()
+                .map(|&(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+                .collect::<HeaderMap>()
+        };
+        let now = 784_111_777; // Sun, 06 Nov 1994 08:49:37 GMT
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "120")]), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[("retry-after", "Sun, 06 Nov 1994 08:50:07 GMT")]),
+                now
+            ),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[("retry-after", "Sun, 06 Nov 1994 08:49:07 GMT")]),
+                now
+            ),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset", "784111837")
+                ]),
+                now
+            ),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[
+                    ("x-ratelimit-remaining", "12"),
+                    ("x-ratelimit-reset", "784111837")
+                ]),
+                now
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let outbox = tempfile::tempdir().unwrap();
+        let poster = poster(outbox.path(), "current");
+        // The token of the request is replaced by the current one
+        let post = |url: &str| {
+            Request::post(url)
+                .header("private-token", "revoked")
+                .body(String::from("state=success"))
+                .unwrap()
+        };
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = mock_forge(&[429, 503], hits.clone()).await;
+        assert_eq!(
+            poster
+                .post(ForgeKind::Gitlab, None, post(&url))
+                .await
+                .unwrap(),
+            StatusCode::CREATED
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(outbox_len(outbox.path()), 0);
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = mock_forge(&[404], hits.clone()).await;
+        assert!(matches!(
+            poster.post(ForgeKind::Gitlab, None, post(&url)).await,
+            Err(PostError::Status(StatusCode::NOT_FOUND, _))
+        ));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(outbox_len(outbox.path()), 0);
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = mock_forge(&[502, 502, 502], hits.clone()).await;
+        assert!(
+            poster
+                .post(ForgeKind::Gitlab, None, post(&url))
+                .await
+                .is_err()
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(outbox_len(outbox.path()), 1);
+        let entry = fs::read_dir(outbox.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert!(
+            !fs::read_to_string(entry.path())
+                .unwrap()
+                .contains("revoked")
+        );
+
+        // The server has recovered when the outbox is flushed
+        poster.flush().await;
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        assert_eq!(outbox_len(outbox.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_superseded_status() {
+        let outbox = tempfile::tempdir().unwrap();
+        let previous = poster(outbox.path(), "current");
+        let post =
+            |url: &str, state: &str| Request::post(url).body(format!("state={state}")).unwrap();
+        let status = || Some(String::from("fedcba98/lab3"));
+
+        // The running status cannot be delivered and stays in the outbox
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = mock_forge(&[502, 502, 502], hits.clone()).await;
+        assert!(
+            previous
+                .post(ForgeKind::Gitlab, status(), post(&url, "running"))
+                .await
+                .is_err()
+        );
+        assert_eq!(outbox_len(outbox.path()), 1);
+
+        // The final status is delivered and drops the running one
+        assert!(
+            previous
+                .post(ForgeKind::Gitlab, status(), post(&url, "success"))
+                .await
+                .is_ok()
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        previous.flush().await;
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        assert_eq!(outbox_len(outbox.path()), 0);
+
+        // Older statuses left in the outbox are dropped on restart as well
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = mock_forge(&[502, 502, 502, 502, 502, 502], hits.clone()).await;
+        for state in ["running", "failed"] {
+            assert!(
+                previous
+                    .post(ForgeKind::Gitlab, status(), post(&url, state))
+                    .await
+                    .is_err()
+            );
+        }
+        assert_eq!(outbox_len(outbox.path()), 2);
+        let restarted = poster(outbox.path(), "current");
+        restarted.flush().await;
+        assert_eq!(hits.load(Ordering::SeqCst), 7);
+        assert_eq!(outbox_len(outbox.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_forks_sharing_a_commit() {
+        let outbox = tempfile::tempdir().unwrap();
+        let poster = poster(outbox.path(), "current");
+        let hook = |project: &str| {
+            serde_json::from_value::<Hook>(serde_json::json!({
+                "object_kind": "push",
+                "checkout_sha": "fedcba9876543210fedcba9876543210fedcba98",
+                "project_id": project,
+                "ref": "refs/heads/main",
+                "repository": {
+                    "name": "compiler",
+                    "homepage": format!("https://gitlab.example.com/{project}/compiler"),
+                    "git_http_url": format!("https://gitlab.example.com/{project}/compiler.git"),
+                },
+            }))
+            .unwrap()
+        };
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = mock_forge(&[502, 502, 502], hits.clone()).await;
+        let post = |project: &str| {
+            poster.post(
+                ForgeKind::Gitlab,
+                Some(hook(project).status_key("lab3")),
+                Request::post(&url)
+                    .body(format!("project={project}"))
+                    .unwrap(),
+            )
+        };
+
+        // The status of the first fork stays in the outbox while the forge
+        // is unreachable, and is not superseded by the one of the second fork
+        assert!(post("1").await.is_err());
+        assert!(post("2").await.is_ok());
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        poster.flush().await;
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+        assert_eq!(outbox_len(outbox.path()), 0);
+    }
 }
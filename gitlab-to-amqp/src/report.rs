@@ -1,37 +1,129 @@
 use std::convert::TryInto;
 
 use amqp_utils::AmqpResponse;
-use gitlab::api::{self, State};
+use graders_utils::errorkind::ErrorKind;
+use graders_utils::logs::PhaseLog;
+use graders_utils::time::unix_time;
+use graders_utils::visibility::Visibility;
 use hyper::Request;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::config::Configuration;
-use crate::gitlab;
+use crate::config::{Configuration, Timeliness};
+use crate::forge::{self, ForgeKind, Hook, State};
+use crate::package;
+use crate::store::{GradeRecord, Store};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Report {
     grade: usize,
     #[serde(rename = "max-grade")]
     max_grade: usize,
     explanation: Option<String>,
+    /// Present when the tests could not be run
+    #[serde(rename = "error-kind")]
+    error_kind: Option<ErrorKind>,
     groups: Option<Vec<Group>>,
 }
 
-#[derive(Deserialize)]
+impl Report {
+    /// The tests could not be run because of a problem on the grader side
+    fn is_grader_error(&self) -> bool {
+        self.error_kind
+            .is_some_and(|error_kind| !error_kind.is_student_fault())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct Group {
     grade: usize,
     #[serde(rename = "max-grade")]
     max_grade: usize,
     description: Option<String>,
+    /// Default visibility of the tests of the group
+    #[serde(default)]
+    visibility: Visibility,
     tests: Vec<Test>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct Test {
     coefficient: usize,
     description: String,
     success: bool,
     signal: Option<u32>,
+    /// Duration in seconds
+    duration: Option<f64>,
+    /// Exit status
+    status: Option<i32>,
+    expected_status: Option<i32>,
+    /// Excerpt of the expected output
+    expected: Option<String>,
+    /// Excerpt of the actual output
+    actual: Option<String>,
+    /// Unified diff between the expected and actual outputs
+    diff: Option<String>,
+    /// Visibility overriding the group one, unless the group is hidden
+    visibility: Option<Visibility>,
+}
+
+impl Test {
+    fn visibility(&self, group: &Group) -> Visibility {
+        group.visibility.of_test(self.visibility)
+    }
+
+    fn has_details(&self) -> bool {
+        self.status.is_some()
+            || self.expected.is_some()
+            || self.actual.is_some()
+            || self.diff.is_some()
+    }
+
+    /// Render the details of a failing test in a collapsible block
+    fn details_to_markdown(&self) -> String {
+        let mut summary = self.description.clone();
+        if let Some(status) = self.status {
+            summary.push_str(&format!(": exit status {status}"));
+            if let Some(expected_status) = self.expected_status {
+                summary.push_str(&format!(" (expected {expected_status})"));
+            }
+        }
+        if let Some(duration) = self.duration {
+            summary.push_str(&format!(" in {duration:.2}s"));
+        }
+        let mut details = format!("<details><summary>{}</summary>\n\n", escape(&summary));
+        if let Some(ref diff) = self.diff {
+            details.push_str(&format!("{}\n", code_block("diff", diff)));
+        } else {
+            if let Some(ref expected) = self.expected {
+                details.push_str(&format!("Expected:\n\n{}\n\n", code_block("", expected)));
+            }
+            if let Some(ref actual) = self.actual {
+                details.push_str(&format!("Actual:\n\n{}\n", code_block("", actual)));
+            }
+        }
+        details.push_str("</details>\n");
+        details
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Put `content` in a fenced code block, using a fence longer than any
+/// run of backticks in the content
+fn code_block(info: &str, content: &str) -> String {
+    let longest = content
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}{info}\n{}\n{fence}", content.trim_end())
 }
 
 fn signal_to_explanation(signal: u32) -> String {
@@ -48,104 +140,234 @@ fn signal_to_explanation(signal: u32) -> String {
     }
 }
 
-fn yaml_to_markdown(lab: &str, yaml: &str) -> eyre::Result<(String, usize, usize)> {
-    let report: Report = serde_yaml::from_str(yaml)?;
-    if let Some(explanation) = report.explanation {
+/// Show the outputs of the last failing command, if any
+fn failing_log_to_markdown(logs: &[PhaseLog]) -> Option<String> {
+    let log = logs.iter().rev().find(|log| !log.success())?;
+    let mut output = log.stdout.clone();
+    output.push_str(&log.stderr);
+    Some(format!(
+        r#"
+
+<details><summary>Output of <code>{}</code> ({} phase{})</summary>
+
+{}
+</details>"#,
+        escape(&log.command),
+        log.phase,
+        if log.truncated { ", truncated" } else { "" },
+        code_block("", &output)
+    ))
+}
+
+/// Render the details of the first `max_details` failing tests which have some
+fn details_to_markdown(report: &Report, max_details: usize) -> String {
+    let details = report
+        .groups
+        .iter()
+        .flatten()
+        .flat_map(|group| {
+            group
+                .tests
+                .iter()
+                .filter(|test| test.visibility(group) == Visibility::Public)
+        })
+        .filter(|test| !test.success && test.has_details())
+        .take(max_details)
+        .map(Test::details_to_markdown)
+        .collect::<Vec<_>>();
+    if details.is_empty() {
+        String::new()
+    } else {
+        format!("\n### Details\n\n{}", details.join("\n"))
+    }
+}
+
+fn report_to_markdown(
+    lab: &str,
+    report: &Report,
+    logs: Option<&[PhaseLog]>,
+    max_details: usize,
+) -> String {
+    if let Some(ref explanation) = report.explanation {
         log::warn!("problem during handling of {lab}: {explanation}");
-        return Ok((
-            format!(
-                r#"## Error
+        return format!(
+            r#"## Error
 
 There has been an error during the test for {lab}:
 
-```
-{explanation}
-```"#
-            ),
-            report.grade,
-            report.max_grade,
-        ));
+{}{}"#,
+            code_block("", explanation),
+            logs.and_then(failing_log_to_markdown).unwrap_or_default()
+        );
     }
     let groups = report
         .groups
-        .unwrap_or_default()
         .iter()
-        .filter(|group| group.grade != group.max_grade)
+        .flatten()
+        .filter(|group| group.grade != group.max_grade && group.visibility != Visibility::Hidden)
         .map(|group| {
-            let tests = if group.grade == 0 {
+            let failing = group
+                .tests
+                .iter()
+                .filter(|test| !test.success && test.visibility(group) != Visibility::Hidden)
+                .map(|test| {
+                    format!(
+                        "- {}{}{}",
+                        if test.visibility(group) == Visibility::Public {
+                            &test.description
+                        } else {
+                            "*hidden test*"
+                        },
+                        if test.coefficient == 1 {
+                            String::new()
+                        } else {
+                            format!(" (coefficient {})", test.coefficient)
+                        },
+                        test.signal.map_or_else(String::new, |s| format!(
+                            " [{}]",
+                            signal_to_explanation(s)
+                        ))
+                    )
+                })
+                .collect::<Vec<_>>();
+            let tests = if group.grade == 0 || failing.is_empty() {
                 String::new()
             } else {
-                let mut explanation = "Failing tests:\n\n".to_owned();
-                explanation.push_str(
-                    &group
-                        .tests
-                        .iter()
-                        .filter(|test| !test.success)
-                        .map(|test| {
-                            format!(
-                                "- {}{}{}",
-                                &test.description,
-                                if test.coefficient == 1 {
-                                    String::new()
-                                } else {
-                                    format!(" (coefficient {})", test.coefficient)
-                                },
-                                test.signal.map_or_else(String::new, |s| format!(
-                                    " [{}]",
-                                    signal_to_explanation(s)
-                                ))
-                            )
-                        })
-                        .collect::<Vec<_>>()
-                        .join("\n"),
-                );
-                explanation
+                format!("Failing tests:\n\n{}", failing.join("\n"))
+            };
+            let description = match (group.visibility, &group.description) {
+                (Visibility::Public, Some(description)) => description.clone(),
+                (Visibility::Public, None) => "*Test group*".to_owned(),
+                _ => "*Hidden test group*".to_owned(),
             };
             format!(
                 "### {} ({})\n\n{}\n",
-                group
-                    .description
-                    .clone()
-                    .unwrap_or_else(|| "*Test group*".to_owned()),
+                description,
                 pass_fail(group.grade, group.max_grade),
                 tests
             )
         })
         .collect::<Vec<_>>()
         .join("\n");
-    let diagnostic = format!(
-        "## Failed tests report for {} ({})\n\n{}",
+    let hidden_failing = report
+        .groups
+        .iter()
+        .flatten()
+        .flat_map(|group| group.tests.iter().map(move |test| (group, test)))
+        .filter(|(group, test)| !test.success && test.visibility(group) == Visibility::Hidden)
+        .count();
+    let groups = match hidden_failing {
+        0 => groups,
+        1 => format!("{groups}\n1 hidden test failing\n"),
+        n => format!("{groups}\n{n} hidden tests failing\n"),
+    };
+    format!(
+        "## Failed tests report for {} ({})\n\n{}{}",
         lab,
         pass_fail(report.grade, report.max_grade),
-        groups
-    );
-    Ok((diagnostic, report.grade, report.max_grade))
+        groups,
+        details_to_markdown(report, max_details)
+    )
+}
+
+fn grade_record(hook: &Hook, lab: &str, report: &Report, timeliness: Timeliness) -> GradeRecord {
+    GradeRecord {
+        repository: hook.repository_url().to_string(),
+        lab: lab.to_owned(),
+        sha: hook.pushed_sha().to_owned(),
+        ref_: hook.short_ref().to_owned(),
+        pushed_at: hook.received_at(),
+        graded_at: unix_time(),
+        grade: report.grade,
+        max_grade: report.max_grade,
+        penalty: match timeliness {
+            Timeliness::Late { penalty, .. } => penalty,
+            _ => 0.0,
+        },
+        submission: hook.submission.is_some(),
+        grader_error: report.is_grader_error(),
+        report: serde_json::to_string(report).unwrap(),
+    }
 }
 
+/// Request to post, with the key of the status it sets, if any
+pub type Post = (Option<String>, Request<String>);
+
 pub fn response_to_post(
     config: &Configuration,
+    store: Option<&Store>,
     response: &AmqpResponse,
-) -> eyre::Result<Vec<Request<String>>> {
-    let (report, grade, max_grade) = yaml_to_markdown(&response.lab, &response.yaml_result)?;
-    let (hook, zip) = gitlab::from_opaque(&response.opaque)?;
-    match gitlab::remove_zip_file(config, &zip) {
+) -> eyre::Result<(ForgeKind, Vec<Post>)> {
+    let report: Report = serde_yaml::from_str(&response.yaml_result)?;
+    let (hook, zip) = package::from_opaque(&response.opaque)?;
+    match package::remove_zip_file(config, &zip) {
         Ok(_) => log::trace!("removed zip file {zip}"),
         Err(e) => log::warn!("could not remove zip file {zip}: {e}"),
     }
+    let timeliness = config
+        .labs
+        .iter()
+        .find(|lab| lab.name == response.lab)
+        .map_or(Timeliness::OnTime, |lab| lab.timeliness(hook.received_at()));
+    if let Some(store) = store {
+        if let Err(e) = store.insert(&grade_record(&hook, &response.lab, &report, timeliness)) {
+            log::error!("cannot store result of {}: {e}", &response.job_name);
+        }
+    }
+    let Some(forge) = forge::forge(config, hook.forge) else {
+        // The forge may have been removed from the configuration since the
+        // job was queued
+        log::error!(
+            "cannot post result of {}: {} is not configured",
+            &response.job_name,
+            hook.forge
+        );
+        return Ok((hook.forge, vec![]));
+    };
+    if report.is_grader_error() {
+        log::error!(
+            "grader error ({:?}) for {}: {}",
+            report.error_kind.unwrap(),
+            &response.job_name,
+            report.explanation.as_deref().unwrap_or("no explanation")
+        );
+        let name = hook.status_name(&response.lab);
+        return Ok((
+            hook.forge,
+            vec![(
+                Some(hook.status_key(&name)),
+                forge.post_status(
+                    &hook,
+                    &State::Canceled,
+                    &name,
+                    Some("grader error, retry later"),
+                ),
+            )],
+        ));
+    }
+    let (grade, max_grade) = (report.grade, report.max_grade);
+    let report = report_to_markdown(
+        &response.lab,
+        &report,
+        response.logs.as_deref(),
+        config.grading.max_failure_details(),
+    );
     let state = if grade == max_grade {
         State::Success
     } else {
         State::Failed
     };
-    let status = api::post_status(
-        &config.gitlab,
-        &hook,
-        &state,
-        hook.branch_name(),
-        &response.lab,
-        Some(&format!("grade: {grade}/{max_grade}")),
+    let name = hook.status_name(&response.lab);
+    let status = (
+        Some(hook.status_key(&name)),
+        forge.post_status(
+            &hook,
+            &state,
+            &name,
+            Some(&status_description(grade, max_grade, timeliness)),
+        ),
     );
-    Ok(if state == State::Success {
+    let posts = if state == State::Success {
         log::info!(
             "tests for {} are a success, generating status only",
             &response.job_name
@@ -158,9 +380,69 @@ pub fn response_to_post(
             grade,
             max_grade
         );
-        let comment = api::post_comment(&config.gitlab, &hook, &report);
-        vec![status, comment]
-    })
+        let mut posts = vec![status];
+        posts.extend(
+            forge
+                .post_comment(&hook, &report)
+                .map(|comment| (None, comment)),
+        );
+        posts
+    };
+    Ok((hook.forge, posts))
+}
+
+fn lateness(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    if days > 0 {
+        format!("{days}d{hours}h")
+    } else if hours > 0 {
+        format!("{hours}h{minutes}m")
+    } else {
+        format!("{}m", minutes.max(1))
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn status_description(grade: usize, max_grade: usize, timeliness: Timeliness) -> String {
+    match timeliness {
+        Timeliness::Late { seconds, penalty } if penalty >= 100.0 => format!(
+            "grade: {grade}/{max_grade}, late by {}, not counted",
+            lateness(seconds)
+        ),
+        Timeliness::Late { seconds, penalty } => format!(
+            "grade: {grade}/{max_grade}, late by {} ({penalty}% penalty), effective grade: {:.1}/{max_grade}",
+            lateness(seconds),
+            grade as f64 * (100.0 - penalty) / 100.0
+        ),
+        _ => format!("grade: {grade}/{max_grade}"),
+    }
+}
+
+#[test]
+fn test_status_description() {
+    assert_eq!(status_description(8, 10, Timeliness::OnTime), "grade: 8/10");
+    assert_eq!(
+        status_description(
+            8,
+            10,
+            Timeliness::Late {
+                seconds: 5400,
+                penalty: 10.0
+            }
+        ),
+        "grade: 8/10, late by 1h30m (10% penalty), effective grade: 7.2/10"
+    );
+    assert_eq!(
+        status_description(
+            8,
+            10,
+            Timeliness::Late {
+                seconds: 200_000,
+                penalty: 100.0
+            }
+        ),
+        "grade: 8/10, late by 2d7h, not counted"
+    );
 }
 
 fn pass_fail(grade: usize, max_grade: usize) -> String {
@@ -174,3 +456,112 @@ fn pass_fail(grade: usize, max_grade: usize) -> String {
         format!("{} failing out of {}", max_grade - grade, max_grade)
     }
 }
+
+#[test]
+fn test_details_to_markdown() {
+    let report: Report = serde_yaml::from_str(
+        r#"
+grade: 1
+max-grade: 3
+groups:
+  - grade: 1
+    max-grade: 3
+    tests:
+      - {coefficient: 1, description: "ok", success: true}
+      - coefficient: 1
+        description: "output"
+        success: false
+        duration: 0.5
+        status: 0
+        diff: "--- expected\n+++ actual\n@@ -1 +1 @@\n-1\n+2\n"
+      - coefficient: 1
+        description: "status"
+        success: false
+        status: 1
+        expected-status: 0
+        actual: "error"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        details_to_markdown(&report, 1),
+        "\n### Details\n\n<details><summary>output: exit status 0 in 0.50s</summary>\n\n```diff\n--- expected\n+++ actual\n@@ -1 +1 @@\n-1\n+2\n```\n</details>\n"
+    );
+    assert!(details_to_markdown(&report, 5).contains(
+        "<summary>status: exit status 1 (expected 0)</summary>\n\nActual:\n\n```\nerror\n```\n"
+    ));
+    assert_eq!(details_to_markdown(&report, 0), "");
+}
+
+#[test]
+fn test_code_block() {
+    assert_eq!(code_block("diff", "-a\n+b\n"), "```diff\n-a\n+b\n```");
+    assert_eq!(
+        code_block("", "```rust\nfn main() {}\n```"),
+        "````\n```rust\nfn main() {}\n```\n````"
+    );
+    let report: Report = serde_yaml::from_str(
+        r#"
+grade: 0
+max-grade: 1
+groups:
+  - grade: 0
+    max-grade: 1
+    description: "Group"
+    tests:
+      - {coefficient: 1, description: "a < b", success: false, actual: "`x`"}
+"#,
+    )
+    .unwrap();
+    assert!(details_to_markdown(&report, 1).contains("<summary>a &lt; b</summary>"));
+}
+
+#[test]
+fn test_hidden_tests() {
+    let report: Report = serde_yaml::from_str(
+        r#"
+grade: 1
+max-grade: 7
+groups:
+  - grade: 1
+    max-grade: 4
+    description: "Public"
+    tests:
+      - {coefficient: 1, description: "ok", success: true}
+      - {coefficient: 1, description: "shown", success: false}
+      - {coefficient: 1, description: "secret", success: false, visibility: hidden-name}
+      - {coefficient: 1, description: "invisible", success: false, visibility: hidden}
+  - grade: 0
+    max-grade: 3
+    description: "Secret group"
+    visibility: hidden
+    tests:
+      - {coefficient: 1, description: "invisible", success: false, status: 1}
+      - {coefficient: 1, description: "invisible", success: false}
+      - {coefficient: 1, description: "invisible", success: false, visibility: public, actual: "invisible"}
+"#,
+    )
+    .unwrap();
+    let markdown = report_to_markdown("lab1", &report, None, 5);
+    assert!(markdown.contains("- shown\n- *hidden test*\n"));
+    assert!(markdown.contains("\n4 hidden tests failing\n"));
+    assert!(!markdown.contains("Details"));
+    assert!(!markdown.contains("secret"));
+    assert!(!markdown.contains("invisible"));
+    assert!(!markdown.contains("Secret group"));
+}
+
+#[test]
+fn test_timeout_report() {
+    let report: Report = serde_yaml::from_str(
+        r#"
+grade: 0
+max-grade: 1
+explanation: timed out after 600 seconds
+error-kind: timeout
+"#,
+    )
+    .unwrap();
+    assert!(!report.is_grader_error());
+    assert!(report_to_markdown("lab3", &report, None, 5).contains("timed out after 600 seconds"));
+}
@@ -0,0 +1,208 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+static SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS results (
+    id INTEGER PRIMARY KEY,
+    repository TEXT NOT NULL,
+    lab TEXT NOT NULL,
+    sha TEXT NOT NULL,
+    ref TEXT NOT NULL,
+    pushed_at INTEGER NOT NULL,
+    graded_at INTEGER NOT NULL,
+    grade INTEGER NOT NULL,
+    max_grade INTEGER NOT NULL,
+    penalty REAL NOT NULL,
+    submission INTEGER NOT NULL,
+    grader_error INTEGER NOT NULL,
+    report TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS results_by_lab ON results (repository, lab, pushed_at);
+";
+
+static COLUMNS: &str = "repository, lab, sha, ref, pushed_at, graded_at, grade, max_grade, \
+                        penalty, submission, grader_error, report";
+
+/// Result of the grading of a lab for a given commit
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GradeRecord {
+    /// Repository home page
+    pub repository: String,
+    pub lab: String,
+    pub sha: String,
+    #[serde(rename = "ref")]
+    pub ref_: String,
+    /// Reception time of the push, in seconds since the Unix epoch
+    pub pushed_at: u64,
+    /// Reception time of the result, in seconds since the Unix epoch
+    pub graded_at: u64,
+    pub grade: usize,
+    pub max_grade: usize,
+    /// Late penalty, in percent of the grade
+    pub penalty: f64,
+    /// The push is an official submission of the lab
+    pub submission: bool,
+    /// The tests could not be run, the grade is meaningless
+    pub grader_error: bool,
+    /// Parsed report, as JSON
+    pub report: String,
+}
+
+impl GradeRecord {
+    /// Grade after the late penalty
+    #[allow(clippy::cast_precision_loss)]
+    pub fn effective_grade(&self) -> f64 {
+        self.grade as f64 * (100.0 - self.penalty) / 100.0
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<GradeRecord> {
+        Ok(GradeRecord {
+            repository: row.get(0)?,
+            lab: row.get(1)?,
+            sha: row.get(2)?,
+            ref_: row.get(3)?,
+            pushed_at: row.get(4)?,
+            graded_at: row.get(5)?,
+            grade: row.get(6)?,
+            max_grade: row.get(7)?,
+            penalty: row.get(8)?,
+            submission: row.get(9)?,
+            grader_error: row.get(10)?,
+            report: row.get(11)?,
+        })
+    }
+}
+
+/// Every grading result, kept in a SQLite database
+pub struct Store(Mutex<Connection>);
+
+impl Store {
+    pub fn open(path: &Path) -> rusqlite::Result<Store> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Store> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Store(Mutex::new(connection)))
+    }
+
+    pub fn insert(&self, record: &GradeRecord) -> rusqlite::Result<()> {
+        self.0.lock().unwrap().execute(
+            &format!(
+                "INSERT INTO results ({COLUMNS}) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"
+            ),
+            params![
+                record.repository,
+                record.lab,
+                record.sha,
+                record.ref_,
+                record.pushed_at,
+                record.graded_at,
+                record.grade,
+                record.max_grade,
+                record.penalty,
+                record.submission,
+                record.grader_error,
+                record.report,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Labs graded at least once for `repository`
+    pub fn labs(&self, repository: &str) -> rusqlite::Result<Vec<String>> {
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT DISTINCT lab FROM results WHERE repository = ?1 ORDER BY lab")?;
+        statement
+            .query_map([repository], |row| row.get(0))?
+            .collect()
+    }
+
+    /// Every result for `lab` in `repository`, including grader errors, from
+    /// the oldest push to the most recent one
+    pub fn history(&self, repository: &str, lab: &str) -> rusqlite::Result<Vec<GradeRecord>> {
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM results WHERE repository = ?1 AND lab = ?2 \
+             ORDER BY pushed_at, id"
+        ))?;
+        statement
+            .query_map([repository, lab], GradeRecord::from_row)?
+            .collect()
+    }
+
+    /// Result of the most recent push
+    pub fn latest(&self, repository: &str, lab: &str) -> rusqlite::Result<Option<GradeRecord>> {
+        self.find_one(repository, lab, "pushed_at DESC, id DESC")
+    }
+
+    /// Result with the highest effective grade, the earliest one on ties
+    pub fn best(&self, repository: &str, lab: &str) -> rusqlite::Result<Option<GradeRecord>> {
+        self.find_one(
+            repository,
+            lab,
+            "grade * (100 - penalty) DESC, pushed_at, id",
+        )
+    }
+
+    /// First result, grader errors excepted, in the given order
+    fn find_one(
+        &self,
+        repository: &str,
+        lab: &str,
+        order: &str,
+    ) -> rusqlite::Result<Option<GradeRecord>> {
+        self.0
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!(
+                    "SELECT {COLUMNS} FROM results \
+                     WHERE repository = ?1 AND lab = ?2 AND NOT grader_error \
+                     ORDER BY {order} LIMIT 1"
+                ),
+                [repository, lab],
+                GradeRecord::from_row,
+            )
+            .optional()
+    }
+}
+
+#[test]
+fn test_store() {
+    let store = Store::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+    let record = |sha: &str, pushed_at, grade, penalty, grader_error| GradeRecord {
+        repository: String::from("https://gitlab.example.com/student/tiger"),
+        lab: String::from("lab3"),
+        sha: String::from(sha),
+        ref_: String::from("master"),
+        pushed_at,
+        graded_at: pushed_at + 60,
+        grade,
+        max_grade: 10,
+        penalty,
+        submission: false,
+        grader_error,
+        report: String::from("{}"),
+    };
+    let records = [
+        record("a", 100, 6, 0.0, false),
+        record("b", 200, 9, 50.0, false),
+        record("c", 300, 8, 0.0, false),
+        record("d", 400, 0, 0.0, true),
+    ];
+    for r in &records {
+        store.insert(r).unwrap();
+    }
+    let repository = "https://gitlab.example.com/student/tiger";
+    assert_eq!(store.labs(repository).unwrap(), vec!["lab3"]);
+    assert_eq!(store.history(repository, "lab3").unwrap(), records);
+    assert_eq!(store.latest(repository, "lab3").unwrap().unwrap().sha, "c");
+    assert_eq!(store.best(repository, "lab3").unwrap().unwrap().sha, "c");
+    assert_eq!(store.latest(repository, "lab4").unwrap(), None);
+    assert!(store.history("other", "lab3").unwrap().is_empty());
+}
@@ -17,14 +17,11 @@ use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
 
 use crate::config::Configuration;
-use crate::gitlab::GitlabHook;
+use crate::forge::{self, ForgeKind, Hook};
 
 #[allow(clippy::module_name_repetitions)]
 #[allow(clippy::too_many_lines)]
-pub async fn web_server(
-    config: &Arc<Configuration>,
-    send_hook: Sender<GitlabHook>,
-) -> eyre::Result<()> {
+pub async fn web_server(config: &Arc<Configuration>, send_hook: Sender<Hook>) -> eyre::Result<()> {
     let config = config.clone();
     let addr = SocketAddr::new(config.server.ip, config.server.port);
     log::info!(
@@ -52,47 +49,57 @@ pub async fn web_server(
                     let (head, body) = req.into_parts();
                     log::trace!("got {} {}", head.method, head.uri.path());
                     match (head.method, head.uri.path()) {
-                        (Method::POST, "/push") => {
+                        (Method::POST, path @ ("/push" | "/github" | "/gitea")) => {
+                            let kind = match path {
+                                "/github" => ForgeKind::Github,
+                                "/gitea" => ForgeKind::Gitea,
+                                _ => ForgeKind::Gitlab,
+                            };
+                            let Some(forge) = forge::forge(&config, kind) else {
+                                log::warn!("received {kind} hook but {kind} is not configured");
+                                return Ok(not_found());
+                            };
                             let body = body.collect().await?.to_bytes();
-                            let hook = serde_json::from_slice::<GitlabHook>(&body)
+                            if let Err(e) = forge.verify(&head.headers, &body) {
+                                log::error!("rejecting {kind} hook: {e}");
+                                return Ok::<_, eyre::Report>(
+                                    Response::builder()
+                                        .status(StatusCode::FORBIDDEN)
+                                        .body(Full::new(Bytes::from(e.to_string())))?,
+                                );
+                            }
+                            let Some(mut hook) = forge
+                                .parse_hook(&head.headers, &body)
                                 .map_err(|e| {
                                     log::error!("error when decoding body: {e}");
                                     e
                                 })
-                                .with_context(|| "error when decoding body")?;
-                            if let Some(secret_token) = config.gitlab.secret_token.clone() {
-                                if let Some(from_request) = head
-                                    .headers
-                                    .get("X-Gitlab-Token")
-                                    .and_then(|h| h.to_str().ok())
-                                {
-                                    if secret_token != from_request {
-                                        log::error!("incorrect secret token sent to the hook");
-                                        return Ok::<_, eyre::Report>(
-                                            Response::builder()
-                                                .status(StatusCode::FORBIDDEN)
-                                                .body(Full::new(Bytes::from(
-                                                    "incorrect secret token",
-                                                )))?,
-                                        );
-                                    }
-                                } else {
-                                    log::error!("missing secret token with hook");
-                                    return Ok::<_, eyre::Report>(
-                                        Response::builder()
-                                            .status(StatusCode::FORBIDDEN)
-                                            .body(Full::new(Bytes::from("missing secret token")))?,
-                                    );
-                                }
+                                .with_context(|| "error when decoding body")?
+                            else {
+                                log::debug!("ignoring unhandled {kind} event");
+                                return Ok(no_content());
+                            };
+                            if let Some(lab) = hook
+                                .tag_name()
+                                .and_then(|tag| config.grading.submission_lab(tag))
+                            {
+                                log::info!("submission of {lab} for {}", hook.desc());
+                                hook.mark_as_submission(lab);
                             }
-                            if hook.object_kind != "push" {
-                                log::trace!(
-                                    "received unknown object kind for {}: {}",
-                                    hook.desc(),
-                                    hook.object_kind
+                            if let Some(branch) = hook
+                                .target_branch()
+                                .filter(|branch| !config.grading.is_merge_request_target(branch))
+                            {
+                                log::debug!(
+                                    "ignoring merge request to {branch} for {}",
+                                    hook.desc()
+                                );
+                            } else if !hook.is_gradable() {
+                                log::debug!(
+                                    "ignoring {} event for {}",
+                                    hook.object_kind,
+                                    hook.desc()
                                 );
-                            } else if hook.is_delete() {
-                                log::debug!("branch deletion event for {}", hook.desc());
                             } else {
                                 log::trace!("received json and will pass it around: {hook:?}");
                                 let mut send_hook = send_hook.clone();
@@ -102,11 +109,7 @@ pub async fn web_server(
                                     }
                                 });
                             }
-                            Ok::<_, eyre::Report>(
-                                Response::builder()
-                                    .status(StatusCode::NO_CONTENT)
-                                    .body(Full::new(Bytes::new()))?,
-                            )
+                            Ok(no_content())
                         }
                         (Method::GET, path) if is_acceptable_path_name(path) => {
                             let path = Path::new(path);
@@ -174,3 +177,10 @@ fn not_found() -> Response<Full<Bytes>> {
         .body(Full::new(Bytes::new()))
         .unwrap()
 }
+
+fn no_content() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
@@ -0,0 +1,36 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Origin of an error preventing the tests from being run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The submitted code does not build
+    BuildError,
+    /// Some submitted files should be executable but are not
+    NotExecutable,
+    /// The submitted archive is corrupt or does not have the expected layout
+    InvalidSubmission,
+    /// The tests did not complete within the allotted time
+    Timeout,
+    /// The test harness could not run or did not complete
+    HarnessError,
+    /// The submitted code could not be retrieved or unpacked
+    DownloadError,
+    /// The grading infrastructure failed
+    InfrastructureError,
+}
+
+impl ErrorKind {
+    /// Errors which are the student's responsibility, as opposed to
+    /// errors on the grader side which may go away by retrying later.
+    #[must_use]
+    pub fn is_student_fault(self) -> bool {
+        matches!(
+            self,
+            ErrorKind::BuildError
+                | ErrorKind::NotExecutable
+                | ErrorKind::InvalidSubmission
+                | ErrorKind::Timeout
+        )
+    }
+}
@@ -1,3 +1,7 @@
 #![allow(clippy::missing_errors_doc)]
 
+pub mod errorkind;
+pub mod logs;
+pub mod time;
+pub mod visibility;
 pub mod ziputils;
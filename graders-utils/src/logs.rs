@@ -0,0 +1,72 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Key under which the builder adds the logs to its YAML output
+pub const LOGS_KEY: &str = "logs";
+
+/// Outputs of a command run during one phase of the build or of the tests
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PhaseLog {
+    /// Phase name, such as `autogen`, `configure`, `make` or `tests`
+    pub phase: String,
+    pub command: String,
+    /// Exit status, absent if the command has been killed by a signal
+    pub status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// The beginning of the outputs has been dropped to respect the size limit
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl PhaseLog {
+    /// Build a log keeping at most the last `max_size` bytes of each output.
+    #[must_use]
+    pub fn new(
+        phase: &str,
+        command: String,
+        status: Option<i32>,
+        stdout: &[u8],
+        stderr: &[u8],
+        max_size: usize,
+    ) -> PhaseLog {
+        let (stdout, stdout_truncated) = tail(stdout, max_size);
+        let (stderr, stderr_truncated) = tail(stderr, max_size);
+        PhaseLog {
+            phase: phase.to_owned(),
+            command,
+            status,
+            stdout,
+            stderr,
+            truncated: stdout_truncated || stderr_truncated,
+        }
+    }
+
+    #[must_use]
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+/// Keep at most the last `max_size` bytes of `output`, where errors are
+/// usually found, without starting in the middle of a UTF-8 sequence.
+fn tail(output: &[u8], max_size: usize) -> (String, bool) {
+    if output.len() <= max_size {
+        return (String::from_utf8_lossy(output).into_owned(), false);
+    }
+    let mut start = output.len() - max_size;
+    while start < output.len() && output[start] & 0xc0 == 0x80 {
+        start += 1;
+    }
+    (String::from_utf8_lossy(&output[start..]).into_owned(), true)
+}
+
+#[test]
+fn test_tail() {
+    assert_eq!(tail(b"make: ok", 16), ("make: ok".to_owned(), false));
+    assert_eq!(tail(b"error: x", 5), ("or: x".to_owned(), true));
+    assert_eq!(
+        tail("erreur: é".as_bytes(), 1),
+        (String::new(), true),
+        "the partial UTF-8 sequence must be dropped"
+    );
+}
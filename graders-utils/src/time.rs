@@ -0,0 +1,26 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time elapsed since the Unix epoch, zero if the clock is set before it
+#[must_use]
+pub fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Current time, in seconds since the Unix epoch
+#[must_use]
+pub fn unix_time() -> u64 {
+    since_epoch().as_secs()
+}
+
+/// Delay to wait after the given failed attempt (starting at 1), starting
+/// at `initial_delay` seconds and doubled after every attempt up to
+/// `max_delay` seconds
+#[must_use]
+pub fn backoff_delay(initial_delay: u64, max_delay: u64, attempt: u32) -> Duration {
+    let factor = 1u64
+        .checked_shl(attempt.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    Duration::from_secs(initial_delay.saturating_mul(factor).min(max_delay))
+}
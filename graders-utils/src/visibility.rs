@@ -0,0 +1,25 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// What students may see about a test or a group of tests
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    /// The result is shown without the description or the outputs
+    HiddenName,
+    /// Only the number of failing hidden tests is shown
+    Hidden,
+}
+
+impl Visibility {
+    /// Visibility of a test of a group with this visibility. The test may
+    /// override the visibility of its group, unless the group is hidden.
+    #[must_use]
+    pub fn of_test(self, test: Option<Visibility>) -> Visibility {
+        match self {
+            Visibility::Hidden => Visibility::Hidden,
+            group => test.unwrap_or(group),
+        }
+    }
+}
@@ -8,6 +8,8 @@ use std::path::{Path, PathBuf};
 use tokio::io::AsyncWriteExt;
 use zip::write::{FileOptions, ZipWriter};
 
+use crate::errorkind::ErrorKind;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ZipError {
     #[error("file name in zip does not start with {0}: `{1:?}'")]
@@ -24,6 +26,21 @@ pub enum ZipError {
     ZipError(#[from] zip::result::ZipError),
 }
 
+impl ZipError {
+    /// Blame the student for archives which cannot be used, and the grader
+    /// for archives which cannot be retrieved
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ZipError::BadPrefix(..) | ZipError::ZipError(_) => ErrorKind::InvalidSubmission,
+            ZipError::CannotWrite(..)
+            | ZipError::CouldNotRetrieve(..)
+            | ZipError::IoError(_)
+            | ZipError::ReqwestError(..) => ErrorKind::DownloadError,
+        }
+    }
+}
+
 /// Unzip `zip_file` in `dir`, ensure that all paths start with the specified
 /// `prefix` directory and a slash and return the path to this directory.
 /// `zip_file` may be an URL starting with `http://` or `https://`.
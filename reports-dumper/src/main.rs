@@ -0,0 +1,120 @@
+mod report;
+mod results;
+
+use amqp_utils::{
+    AmqpChannel, AmqpConfiguration, AmqpDelivery, AmqpError, AmqpResponse, AmqpSupervisor, Envelope,
+};
+use clap::Parser;
+use futures::StreamExt;
+use report::{BadMessage, Entry};
+use results::{Format, Results};
+use serde_derive::Deserialize;
+use std::error::Error;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+
+const CONSUMER_TAG: &str = "reports-dumper";
+
+/// Keep the latest and highest grades of every repository for every lab,
+/// as received on the reports queue.
+///
+/// Logging is enabled by setting `RUST_LOG` to the desired level.
+#[derive(Parser)]
+pub struct Opt {
+    /// Configuration file containing the AMQP section
+    #[clap(short, long)]
+    config: PathBuf,
+
+    /// Format of the output file
+    #[clap(short, long, value_enum, default_value = "csv")]
+    format: Format,
+
+    /// Output file, loaded at startup and rewritten after every report
+    output: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Configuration {
+    amqp: AmqpConfiguration,
+}
+
+/// Decode a report received on the queue
+fn decode(msg: &AmqpDelivery) -> Result<Option<Entry>, BadMessage> {
+    let response = msg
+        .decode_payload::<Envelope<AmqpResponse>>()
+        .map_err(|e| {
+            BadMessage::Decode(
+                e.source()
+                    .map_or_else(|| e.to_string(), ToString::to_string),
+            )
+        })?;
+    Entry::from_response(&response.payload)
+}
+
+/// Consume reports until the consumer is cancelled, which is an error, or
+/// until the output cannot be written, which is stored in `write_error`.
+async fn consume(
+    channel: &AmqpChannel,
+    queue: &str,
+    opt: &Opt,
+    results: &mut Results,
+    write_error: &mut Option<eyre::Report>,
+) -> Result<(), AmqpError> {
+    let stream = channel.basic_consume(queue, CONSUMER_TAG).await?;
+    log::info!("listening onto the {queue} queue");
+    let mut stream = pin!(stream);
+    while let Some(msg) = stream.next().await {
+        let msg = msg?;
+        match decode(&msg) {
+            Ok(Some(entry)) => {
+                log::info!("{} got {}% for {}", entry.name, entry.percent, entry.lab);
+                results.add(&entry);
+                if let Err(e) = results.save(&opt.output, opt.format) {
+                    // The report will be redelivered after a restart
+                    channel.basic_nack(msg.delivery_tag(), true).await?;
+                    *write_error = Some(e);
+                    return Ok(());
+                }
+                channel.basic_ack(msg.delivery_tag()).await?;
+            }
+            Ok(None) => {
+                log::info!("ignoring report of a grader error");
+                channel.basic_ack(msg.delivery_tag()).await?;
+            }
+            Err(e) => {
+                log::warn!("rejecting report: {e}");
+                channel.basic_reject(msg.delivery_tag(), false).await?;
+            }
+        }
+    }
+    Err(AmqpError::ConsumerCancelled(queue.to_owned()))
+}
+
+fn load_configuration(path: &Path) -> eyre::Result<Configuration> {
+    Ok(serde_yaml::from_reader(File::open(path)?)?)
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    env_logger::init();
+    color_eyre::install()?;
+    let opt = Opt::parse();
+    let config = load_configuration(&opt.config)?;
+    let Some(ref queue) = config.amqp.reports_routing_key else {
+        eyre::bail!("no reports_routing_key in the amqp section of the configuration file");
+    };
+    let mut results = Results::load(&opt.output, opt.format)?;
+    let mut write_error = None;
+    AmqpSupervisor::new(&config.amqp)
+        .run(async |conn| {
+            let channel = conn.create_channel().await?;
+            channel.queue_declare_durable(queue).await?;
+            consume(&channel, queue, &opt, &mut results, &mut write_error).await
+        })
+        .await?;
+    match write_error {
+        Some(e) => Err(e.wrap_err(format!("cannot write {:?}", opt.output))),
+        None => Ok(()),
+    }
+}
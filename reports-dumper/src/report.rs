@@ -0,0 +1,111 @@
+use amqp_utils::AmqpResponse;
+use graders_utils::errorkind::ErrorKind;
+use serde::de::IgnoredAny;
+use serde_derive::Deserialize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BadMessage {
+    #[error("cannot decode response: {0}")]
+    Decode(String),
+    #[error("cannot decode opaque data of job {1}")]
+    Opaque(#[source] serde_json::Error, String),
+    #[error("cannot decode result of job {1}")]
+    Result(#[source] serde_yaml::Error, String),
+    #[error("no maximum grade in result of job {0}")]
+    NoMaxGrade(String),
+}
+
+/// Grade obtained by a repository for a lab
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub lab: String,
+    /// Percentage rounded to one decimal
+    pub percent: f64,
+}
+
+/// Hook stored by `gitlab-to-amqp` in the opaque data, along with the name
+/// of the zip file
+#[derive(Deserialize)]
+struct Hook {
+    repository: Repository,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Diagnostic {
+    grade: usize,
+    #[serde(rename = "max-grade")]
+    max_grade: usize,
+    #[serde(rename = "error-kind")]
+    error_kind: Option<ErrorKind>,
+}
+
+impl Entry {
+    /// Extract the grade from a response, or return `None` if the tests could
+    /// not be run because of a problem on the grader side.
+    pub fn from_response(response: &AmqpResponse) -> Result<Option<Entry>, BadMessage> {
+        let (hook, _): (Hook, IgnoredAny) = serde_json::from_str(&response.opaque)
+            .map_err(|e| BadMessage::Opaque(e, response.job_name.clone()))?;
+        let result: Diagnostic = serde_yaml::from_str(&response.yaml_result)
+            .map_err(|e| BadMessage::Result(e, response.job_name.clone()))?;
+        if result
+            .error_kind
+            .is_some_and(|error_kind| !error_kind.is_student_fault())
+        {
+            return Ok(None);
+        }
+        if result.max_grade == 0 {
+            return Err(BadMessage::NoMaxGrade(response.job_name.clone()));
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let percent = (1000.0 * result.grade as f64 / result.max_grade as f64).round() / 10.0;
+        Ok(Some(Entry {
+            name: hook.repository.name,
+            lab: response.lab.clone(),
+            percent,
+        }))
+    }
+}
+
+#[test]
+fn test_from_response() {
+    let response = |yaml_result: &str| AmqpResponse {
+        job_name: String::from("job"),
+        lab: String::from("lab3"),
+        opaque: String::from(
+            r#"[{"repository": {"name": "tiger", "homepage": "https://example.com/"}}, "a.zip"]"#,
+        ),
+        yaml_result: yaml_result.to_owned(),
+        logs: None,
+        result_queue: String::new(),
+        delivery_tag: 0,
+    };
+    assert_eq!(
+        Entry::from_response(&response("grade: 2\nmax-grade: 3\n")).unwrap(),
+        Some(Entry {
+            name: String::from("tiger"),
+            lab: String::from("lab3"),
+            percent: 66.7
+        })
+    );
+    assert_eq!(
+        Entry::from_response(&response(
+            "grade: 0\nmax-grade: 0\nerror-kind: infrastructure-error\n"
+        ))
+        .unwrap(),
+        None
+    );
+    assert!(matches!(
+        Entry::from_response(&response("grade: 0\nmax-grade: 0\n")),
+        Err(BadMessage::NoMaxGrade(_))
+    ));
+    assert!(matches!(
+        Entry::from_response(&response("grade: [")),
+        Err(BadMessage::Result(..))
+    ));
+}
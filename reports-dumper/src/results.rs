@@ -0,0 +1,191 @@
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::report::Entry;
+
+const BOM: &str = "\u{feff}";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Comma-separated values, one line per repository
+    Csv,
+    /// Object indexed by repository then by lab
+    Json,
+    /// Semicolon-separated values with CRLF line endings and a byte order
+    /// mark, as expected by spreadsheets
+    XlsxCsv,
+}
+
+impl Format {
+    fn delimiter(self) -> u8 {
+        if self == Format::XlsxCsv { b';' } else { b',' }
+    }
+}
+
+/// Percentages obtained for a lab
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct LabResult {
+    pub latest: f64,
+    pub highest: f64,
+}
+
+/// Results indexed by repository name then by lab
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Results(BTreeMap<String, BTreeMap<String, LabResult>>);
+
+impl Results {
+    pub fn add(&mut self, entry: &Entry) {
+        let result = self
+            .0
+            .entry(entry.name.clone())
+            .or_default()
+            .entry(entry.lab.clone())
+            .or_default();
+        result.latest = entry.percent;
+        result.highest = result.highest.max(entry.percent);
+    }
+
+    /// Load the results previously written to `path`, if it exists
+    pub fn load(path: &Path, format: Format) -> eyre::Result<Results> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Results::default()),
+            Err(e) => return Err(e.into()),
+        };
+        if format == Format::Json {
+            return Ok(serde_json::from_str(&content)?);
+        }
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(format.delimiter())
+            .from_reader(content.strip_prefix(BOM).unwrap_or(&content).as_bytes());
+        let headers = reader.headers()?.clone();
+        let mut results = Results::default();
+        for record in reader.records() {
+            let record = record?;
+            let Some(name) = record.get(0) else { continue };
+            for (header, value) in headers.iter().zip(record.iter()).skip(1) {
+                let Ok(percent) = value.parse::<f64>() else {
+                    continue;
+                };
+                let (lab, highest) = if let Some(lab) = header.strip_suffix(" (highest %)") {
+                    (lab, true)
+                } else if let Some(lab) = header.strip_suffix(" (latest %)") {
+                    (lab, false)
+                } else {
+                    continue;
+                };
+                let result = results
+                    .0
+                    .entry(name.to_owned())
+                    .or_default()
+                    .entry(lab.to_owned())
+                    .or_default();
+                if highest {
+                    result.highest = percent;
+                } else {
+                    result.latest = percent;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    fn to_bytes(&self, format: Format) -> eyre::Result<Vec<u8>> {
+        if format == Format::Json {
+            return Ok(serde_json::to_vec_pretty(self)?);
+        }
+        let labs = self
+            .0
+            .values()
+            .flat_map(BTreeMap::keys)
+            .collect::<BTreeSet<_>>();
+        let mut output = Vec::new();
+        let mut writer = csv::WriterBuilder::new();
+        writer.delimiter(format.delimiter());
+        if format == Format::XlsxCsv {
+            output.extend_from_slice(BOM.as_bytes());
+            writer.terminator(csv::Terminator::CRLF);
+        }
+        let mut writer = writer.from_writer(output);
+        let mut header = vec![String::from("name")];
+        for lab in &labs {
+            header.push(format!("{lab} (highest %)"));
+            header.push(format!("{lab} (latest %)"));
+        }
+        writer.write_record(&header)?;
+        for (name, results) in &self.0 {
+            let mut record = vec![name.clone()];
+            for lab in &labs {
+                match results.get(*lab) {
+                    Some(result) => {
+                        record.push(result.highest.to_string());
+                        record.push(result.latest.to_string());
+                    }
+                    None => record.extend([String::new(), String::new()]),
+                }
+            }
+            writer.write_record(&record)?;
+        }
+        Ok(writer.into_inner()?)
+    }
+
+    /// Replace `path` atomically, so that readers never see a partial file
+    pub fn save(&self, path: &Path, format: Format) -> eyre::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(&self.to_bytes(format)?)?;
+        file.as_file().sync_all()?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_save_and_load() {
+    let mut results = Results::default();
+    let entry = |name: &str, lab: &str, percent| Entry {
+        name: name.to_owned(),
+        lab: lab.to_owned(),
+        percent,
+    };
+    results.add(&entry("tiger", "lab3", 80.0));
+    results.add(&entry("tiger", "lab3", 66.7));
+    results.add(&entry("dragon", "lab4", 100.0));
+    assert_eq!(
+        results.0["tiger"]["lab3"],
+        LabResult {
+            latest: 66.7,
+            highest: 80.0
+        }
+    );
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("results");
+    results.save(&path, Format::Csv).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "name,lab3 (highest %),lab3 (latest %),lab4 (highest %),lab4 (latest %)\n\
+         dragon,,,100,100\n\
+         tiger,80,66.7,,\n"
+    );
+    for format in [Format::Csv, Format::Json, Format::XlsxCsv] {
+        results.save(&path, format).unwrap();
+        assert_eq!(Results::load(&path, format).unwrap(), results);
+    }
+    assert!(
+        fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("\u{feff}name;lab3 (highest %);")
+    );
+    assert_eq!(
+        Results::load(&dir.path().join("missing"), Format::Csv).unwrap(),
+        Results::default()
+    );
+}
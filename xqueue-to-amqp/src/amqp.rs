@@ -0,0 +1,111 @@
+use amqp_utils::{
+    self, AmqpChannel, AmqpError, AmqpRequest, AmqpResponse, AmqpSupervisor, Envelope,
+};
+use futures::channel::mpsc::{Receiver, Sender};
+use futures::{SinkExt, StreamExt, try_join};
+use std::error::Error;
+use std::pin::pin;
+
+use crate::config::Configuration;
+
+/// Header holding the lab name, to help inspecting jobs with AMQP tools
+static LAB_HEADER: &str = "x-grader-lab";
+
+/// Publish job requests. A request whose publication failed is kept in
+/// `pending` so that it can be published again after a reconnection.
+async fn amqp_publisher(
+    channel: AmqpChannel,
+    config: &Configuration,
+    receive_request: &mut Receiver<AmqpRequest>,
+    pending: &mut Option<Envelope<AmqpRequest>>,
+) -> Result<(), AmqpError> {
+    loop {
+        let req = match pending.take() {
+            Some(req) => req,
+            None => match receive_request.next().await {
+                Some(req) => Envelope::new(crate::PRODUCER, req),
+                None => return Ok(()),
+            },
+        };
+        log::info!("publishing AMQP job request {}", req.payload.job_name);
+        let properties = req
+            .properties()
+            .with_reply_to(&config.xqueue.amqp_queue)
+            .with_header(LAB_HEADER, &req.payload.lab);
+        if let Err(e) = channel
+            .basic_publish_confirmed(
+                &config.amqp.exchange,
+                &config.amqp.routing_key,
+                &req,
+                &properties,
+            )
+            .await
+        {
+            *pending = Some(req);
+            return Err(e);
+        }
+    }
+}
+
+/// Forward the results to `send_response`. Results which cannot be decoded
+/// are rejected.
+async fn amqp_receiver(
+    channel: AmqpChannel,
+    queue: &str,
+    mut send_response: Sender<AmqpResponse>,
+) -> Result<(), AmqpError> {
+    channel.queue_declare_durable(queue).await?;
+    let stream = channel.basic_consume(queue, "xqueue-to-amqp").await?;
+    log::info!("listening onto the {queue} queue");
+    let mut stream = pin!(stream);
+    while let Some(msg) = stream.next().await {
+        let msg = msg?;
+        match msg.decode_payload::<Envelope<AmqpResponse>>() {
+            Ok(response) => {
+                channel.basic_ack(msg.delivery_tag()).await?;
+                log::debug!(
+                    "received response {} (schema version {}) to request {:?}",
+                    response.payload.job_name,
+                    response.schema_version,
+                    response.correlation_id.as_deref().or(msg.correlation_id())
+                );
+                send_response.send(response.payload).await?;
+            }
+            Err(e) => {
+                log::warn!(
+                    "rejecting undecodable response: {}",
+                    e.source()
+                        .map_or_else(|| e.to_string(), ToString::to_string)
+                );
+                channel.basic_reject(msg.delivery_tag(), false).await?;
+            }
+        }
+    }
+    log::warn!("terminating listening onto the {queue} queue");
+    Err(AmqpError::ConsumerCancelled(queue.to_owned()))
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub async fn amqp_process(
+    config: &Configuration,
+    mut receive_request: Receiver<AmqpRequest>,
+    send_response: Sender<AmqpResponse>,
+) -> Result<(), AmqpError> {
+    let mut pending = None;
+    AmqpSupervisor::new(&config.amqp)
+        .run(async |conn| {
+            let publisher = {
+                let channel = conn.create_channel().await?;
+                channel.declare_exchange_and_queue(&config.amqp).await?;
+                channel.confirm_select().await?;
+                amqp_publisher(channel, config, &mut receive_request, &mut pending)
+            };
+            let receiver = {
+                let channel = conn.create_channel().await?;
+                amqp_receiver(channel, &config.xqueue.amqp_queue, send_response.clone())
+            };
+            try_join!(publisher, receiver)?;
+            Ok(())
+        })
+        .await
+}
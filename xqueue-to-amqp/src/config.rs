@@ -0,0 +1,47 @@
+use amqp_utils::AmqpConfiguration;
+use serde_derive::Deserialize;
+use std::fs::File;
+use std::time::Duration;
+use url::Url;
+
+#[derive(Deserialize)]
+pub struct Configuration {
+    pub xqueue: XQueueConfiguration,
+    pub amqp: AmqpConfiguration,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct XQueueConfiguration {
+    pub base_url: Url,
+    pub username: String,
+    pub password: String,
+    /// Name of the XQueue queue, the username if unset
+    pub queue_name: Option<String>,
+    /// AMQP queue receiving the results
+    pub amqp_queue: String,
+    /// Delay in seconds between polls of an empty queue
+    pub poll_delay: u64,
+    /// Name of the zip file submitted by students
+    pub zip_name: String,
+    /// Name of the mandatory top-level directory in the zip file
+    #[serde(default = "default_dir")]
+    pub dir: String,
+}
+
+fn default_dir() -> String {
+    String::from("dragon-tiger")
+}
+
+impl XQueueConfiguration {
+    pub fn queue_name(&self) -> &str {
+        self.queue_name.as_deref().unwrap_or(&self.username)
+    }
+
+    pub fn poll_delay(&self) -> Duration {
+        Duration::from_secs(self.poll_delay)
+    }
+}
+
+pub fn load_configuration(file: &str) -> eyre::Result<Configuration> {
+    Ok(serde_yaml::from_reader(File::open(file)?)?)
+}
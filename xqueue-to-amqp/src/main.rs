@@ -0,0 +1,138 @@
+mod amqp;
+mod config;
+mod report;
+mod xqueue;
+
+use amqp_utils::{AmqpRequest, AmqpResponse};
+use clap::{arg, command};
+use config::Configuration;
+use futures::channel::mpsc::{self, Receiver, Sender};
+use futures::{SinkExt, StreamExt, TryFutureExt, try_join};
+use xqueue::{Grade, XQueue, XQueueError};
+
+/// Name used to identify the messages produced by this program
+static PRODUCER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+fn configuration() -> eyre::Result<Configuration> {
+    let matches = command!()
+        .arg(arg!(-c --config <FILE> "Configuration file containing credentials").required(true))
+        .get_matches();
+    config::load_configuration(matches.get_one::<String>("config").unwrap())
+}
+
+/// Move the submissions waiting in XQueue to `send_request`, and return
+/// their number.
+async fn fetch_submissions(
+    config: &Configuration,
+    xqueue: &XQueue,
+    send_request: &mut Sender<AmqpRequest>,
+) -> eyre::Result<usize> {
+    if !xqueue.is_logged_in() {
+        xqueue.login().await?;
+    }
+    let len = xqueue.queue_len().await?;
+    log::debug!("polling XQueue ({len} submissions waiting)");
+    // Submissions are retrieved one at a time to avoid being throttled
+    for _ in 0..len {
+        let submission = match xqueue.get_submission().await {
+            Ok(submission) => submission,
+            // The submission has left the queue, the student must get an answer
+            Err(XQueueError::Invalid(header, submission_id, e)) => {
+                log::error!("rejecting submission {submission_id}: {e}");
+                put_result(xqueue, &header, &report::invalid_submission(&e)).await?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        log::info!(
+            "received submission {} for {}",
+            submission.submission_id,
+            submission.lab
+        );
+        send_request
+            .send(AmqpRequest {
+                job_name: format!("xqueue:{}", submission.submission_id),
+                lab: submission.lab,
+                dir: config.xqueue.dir.clone(),
+                zip_url: submission.zip_url,
+                result_queue: config.xqueue.amqp_queue.clone(),
+                opaque: submission.header,
+                delivery_tag: None,
+            })
+            .await?;
+    }
+    Ok(len)
+}
+
+/// Poll XQueue, waiting `poll_delay` after the queue has been found empty
+/// as requested by the edX documentation.
+async fn poll_xqueue(
+    config: &Configuration,
+    xqueue: &XQueue,
+    mut send_request: Sender<AmqpRequest>,
+) -> eyre::Result<()> {
+    loop {
+        match fetch_submissions(config, xqueue, &mut send_request).await {
+            Ok(0) => (),
+            Ok(_) => continue,
+            Err(e) => {
+                log::warn!("cannot poll XQueue: {e}");
+                xqueue.logout();
+            }
+        }
+        tokio::time::sleep(config.xqueue.poll_delay()).await;
+    }
+}
+
+/// Post a result, logging in again if the session has expired
+async fn put_result(xqueue: &XQueue, header: &str, grade: &Grade) -> Result<(), XQueueError> {
+    if xqueue.is_logged_in() && xqueue.put_result(header, grade).await.is_ok() {
+        return Ok(());
+    }
+    xqueue.login().await?;
+    xqueue.put_result(header, grade).await
+}
+
+async fn post_results(
+    xqueue: &XQueue,
+    receive_response: Receiver<AmqpResponse>,
+) -> eyre::Result<()> {
+    receive_response
+        .for_each(|response| async move {
+            let grade = match report::yaml_to_grade(&response.yaml_result) {
+                Ok(grade) => grade,
+                Err(e) => {
+                    log::error!("cannot decode result of {}: {e}", response.job_name);
+                    return;
+                }
+            };
+            match put_result(xqueue, &response.opaque, &grade).await {
+                Ok(()) => log::info!("posted result of {}", response.job_name),
+                // The submission key expires if the result is not posted in
+                // a timely manner, and the student must submit again.
+                Err(e) => log::warn!(
+                    "cannot post result of {}, the submission may have expired: {e}",
+                    response.job_name
+                ),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    env_logger::init();
+    color_eyre::install()?;
+    log::info!("starting");
+    let config = configuration()?;
+    let xqueue = XQueue::new(&config.xqueue);
+    let (send_request, receive_request) = mpsc::channel(16);
+    let (send_response, receive_response) = mpsc::channel(16);
+    let poller = poll_xqueue(&config, &xqueue, send_request);
+    let amqp_process =
+        amqp::amqp_process(&config, receive_request, send_response).map_err(Into::into);
+    let poster = post_results(&xqueue, receive_response);
+    try_join!(poller, amqp_process, poster)?;
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use graders_utils::errorkind::ErrorKind;
+use graders_utils::visibility::Visibility;
+use serde_derive::Deserialize;
+
+use crate::xqueue::{Grade, XQueueError};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Report {
+    grade: usize,
+    max_grade: usize,
+    explanation: Option<String>,
+    error_kind: Option<ErrorKind>,
+    groups: Option<Vec<Group>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Group {
+    grade: usize,
+    max_grade: usize,
+    description: Option<String>,
+    #[serde(default)]
+    visibility: Visibility,
+    tests: Vec<Test>,
+}
+
+#[derive(Deserialize)]
+struct Test {
+    coefficient: usize,
+    description: String,
+    success: bool,
+    signal: Option<u32>,
+    visibility: Option<Visibility>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn signal_to_explanation(signal: u32) -> &'static str {
+    match signal {
+        4 => "illegal instruction",
+        6 => "abort, possibly because of a failed assertion",
+        8 => "arithmetic exception",
+        9 => "program killed, possibly because of an infinite loop or memory exhaustion",
+        10 => "bus error",
+        11 => "segmentation fault",
+        _ => "crash",
+    }
+}
+
+fn groups_to_html(groups: &[Group]) -> String {
+    let mut msg = String::new();
+    let mut hidden_failures = 0;
+    for group in groups {
+        if group.visibility == Visibility::Hidden {
+            // Tests of hidden groups are hidden whatever their own visibility
+            hidden_failures += group.tests.iter().filter(|test| !test.success).count();
+            continue;
+        }
+        let description = match group.description {
+            Some(_) if group.visibility == Visibility::HiddenName => "Hidden test group",
+            Some(ref description) => description,
+            None => "Tests",
+        };
+        let mut failures = String::new();
+        for test in group.tests.iter().filter(|test| !test.success) {
+            let description = match group.visibility.of_test(test.visibility) {
+                Visibility::Public => format!("<b>{}</b>", escape(&test.description)),
+                Visibility::HiddenName => String::from("<i>hidden test</i>"),
+                Visibility::Hidden => {
+                    hidden_failures += 1;
+                    continue;
+                }
+            };
+            let explanation = test.signal.map_or_else(String::new, |signal| {
+                format!(": {}", signal_to_explanation(signal))
+            });
+            failures.push_str(&format!(
+                "<li>{description} (coefficient {}) failed{explanation}</li>",
+                test.coefficient
+            ));
+        }
+        msg.push_str(&format!(
+            "<h2>{} (grade {} / {})</h2>",
+            escape(description),
+            group.grade,
+            group.max_grade
+        ));
+        if !failures.is_empty() {
+            msg.push_str(&format!("<ul>{failures}</ul>"));
+        }
+    }
+    if hidden_failures > 0 {
+        msg.push_str(&format!(
+            "<p>{hidden_failures} hidden test{} failing</p>",
+            if hidden_failures == 1 { "" } else { "s" }
+        ));
+    }
+    msg
+}
+
+/// Grade of a submission which cannot be decoded, and thus cannot be graded
+pub fn invalid_submission(error: &XQueueError) -> Grade {
+    Grade {
+        correct: false,
+        score: 0,
+        msg: format!(
+            "<h1>Your submission could not be graded:</h1><pre>{}</pre>",
+            escape(&error.to_string())
+        ),
+    }
+}
+
+/// Convert the diagnostic of the builder into the grade shown to the student
+pub fn yaml_to_grade(yaml_result: &str) -> Result<Grade, serde_yaml::Error> {
+    let report: Report = serde_yaml::from_str(yaml_result)?;
+    let grader_error = report
+        .error_kind
+        .is_some_and(|error_kind| !error_kind.is_student_fault());
+    let correct = !grader_error && report.grade == report.max_grade;
+    let msg = if grader_error {
+        String::from(
+            "<h1>Your submission could not be graded because of a grader error, \
+             please submit it again later.</h1>",
+        )
+    } else if correct {
+        String::from("<h1>Congratulations, all tests passed!</h1>")
+    } else if let Some(ref explanation) = report.explanation {
+        format!(
+            "<h1>There has been an error with your submission:</h1><pre>{}</pre>",
+            escape(explanation)
+        )
+    } else {
+        format!(
+            "<h1>Some tests are failing in your submission:</h1>{}",
+            groups_to_html(report.groups.as_deref().unwrap_or_default())
+        )
+    };
+    Ok(Grade {
+        correct,
+        score: report.grade,
+        msg,
+    })
+}
+
+#[test]
+fn test_yaml_to_grade() {
+    let grade = yaml_to_grade(
+        r#"
+grade: 1
+max-grade: 5
+groups:
+  - description: "Parsing <1>"
+    grade: 1
+    max-grade: 3
+    tests:
+      - {coefficient: 1, description: "int", success: true}
+      - {coefficient: 2, description: "string", success: false, signal: 11}
+  - description: "Secret"
+    grade: 0
+    max-grade: 2
+    visibility: hidden
+    tests:
+      - {coefficient: 1, description: "a", success: false}
+      - {coefficient: 1, description: "b", success: false, visibility: public}
+"#,
+    )
+    .unwrap();
+    assert!(!grade.correct);
+    assert_eq!(grade.score, 1);
+    assert_eq!(
+        grade.msg,
+        "<h1>Some tests are failing in your submission:</h1>\
+         <h2>Parsing &lt;1&gt; (grade 1 / 3)</h2>\
+         <ul><li><b>string</b> (coefficient 2) failed: segmentation fault</li></ul>\
+         <p>2 hidden tests failing</p>"
+    );
+    let grade =
+        yaml_to_grade("grade: 0\nmax-grade: 0\nerror-kind: harness-error\nexplanation: oops\n")
+            .unwrap();
+    assert!(!grade.correct);
+    assert!(grade.msg.contains("grader error"));
+    let grade =
+        yaml_to_grade("grade: 0\nmax-grade: 5\nexplanation: \"make: *** [all]\"\n").unwrap();
+    assert_eq!(
+        grade.msg,
+        "<h1>There has been an error with your submission:</h1><pre>make: *** [all]</pre>"
+    );
+}
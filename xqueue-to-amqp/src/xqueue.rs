@@ -0,0 +1,432 @@
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, COOKIE, SET_COOKIE};
+use hyper::{Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use url::{Url, form_urlencoded};
+
+use crate::config::XQueueConfiguration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum XQueueError {
+    #[error("cannot reach XQueue")]
+    Http(#[from] hyper_util::client::legacy::Error),
+    #[error("cannot read XQueue answer")]
+    Body(#[from] hyper::Error),
+    #[error("error status {0} for {1}")]
+    Status(StatusCode, String),
+    #[error("cannot decode XQueue answer")]
+    Json(#[from] serde_json::Error),
+    #[error("XQueue refused {0}: {1}")]
+    Refused(&'static str, serde_json::Value),
+    #[error("no {0} file in submission {1}")]
+    MissingFile(String, u64),
+    #[error("invalid submission content: {0}")]
+    Content(serde_json::Error),
+    /// The submission has been taken out of the queue, and must be answered
+    /// with its header
+    #[error("cannot decode submission {1}: {2}")]
+    Invalid(String, u64, Box<XQueueError>),
+}
+
+/// Answer to every XQueue request
+#[derive(Deserialize)]
+struct Answer {
+    return_code: i32,
+    content: serde_json::Value,
+}
+
+/// Submission waiting to be graded. XQueue encodes the inner objects as JSON
+/// strings.
+#[derive(Deserialize)]
+struct RawSubmission {
+    xqueue_header: String,
+    xqueue_body: String,
+    xqueue_files: String,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    submission_id: u64,
+}
+
+#[derive(Deserialize)]
+struct Body {
+    grader_payload: String,
+}
+
+#[derive(Deserialize)]
+struct GraderPayload {
+    lab: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Submission {
+    /// Header to give back with the result
+    pub header: String,
+    pub submission_id: u64,
+    pub lab: String,
+    pub zip_url: String,
+}
+
+/// Result of a submission, as shown to the student
+#[derive(Debug, Serialize)]
+pub struct Grade {
+    pub correct: bool,
+    pub score: usize,
+    /// HTML message
+    pub msg: String,
+}
+
+pub struct XQueue {
+    config: XQueueConfiguration,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    /// Session cookies set by the login
+    cookies: Mutex<Option<String>>,
+}
+
+impl XQueue {
+    pub fn new(config: &XQueueConfiguration) -> XQueue {
+        XQueue {
+            config: config.clone(),
+            client: Client::builder(TokioExecutor::new()).build(HttpsConnector::new()),
+            cookies: Mutex::new(None),
+        }
+    }
+
+    fn url(&self, path: &str) -> Url {
+        let base = self.config.base_url.as_str().trim_end_matches('/');
+        Url::parse(&format!("{base}/xqueue/{path}/")).unwrap()
+    }
+
+    async fn send(&self, request: Request<Full<Bytes>>) -> Result<Answer, XQueueError> {
+        let uri = request.uri().to_string();
+        log::trace!("{} {uri}", request.method());
+        let response = self.client.request(request).await?;
+        if response.status() != StatusCode::OK {
+            return Err(XQueueError::Status(response.status(), uri));
+        }
+        let cookies = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|cookie| cookie.to_str().ok()?.split(';').next())
+            .collect::<Vec<_>>();
+        if !cookies.is_empty() {
+            *self.cookies.lock().unwrap() = Some(cookies.join("; "));
+        }
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    fn request(&self, builder: hyper::http::request::Builder) -> hyper::http::request::Builder {
+        match *self.cookies.lock().unwrap() {
+            Some(ref cookies) => builder.header(COOKIE, cookies),
+            None => builder,
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<Answer, XQueueError> {
+        let mut url = self.url(path);
+        url.query_pairs_mut()
+            .append_pair("queue_name", self.config.queue_name());
+        let request = self
+            .request(Request::get(url.as_str()))
+            .body(Full::default())
+            .unwrap();
+        self.send(request).await
+    }
+
+    async fn post(&self, path: &str, params: &[(&str, &str)]) -> Result<Answer, XQueueError> {
+        let params = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+        let request = self
+            .request(Request::post(self.url(path).as_str()))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Full::new(Bytes::from(params)))
+            .unwrap();
+        self.send(request).await
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.cookies.lock().unwrap().is_some()
+    }
+
+    /// Forget the session, so that the next login starts a new one
+    pub fn logout(&self) {
+        *self.cookies.lock().unwrap() = None;
+    }
+
+    pub async fn login(&self) -> Result<(), XQueueError> {
+        self.logout();
+        let answer = self
+            .post(
+                "login",
+                &[
+                    ("username", &self.config.username),
+                    ("password", &self.config.password),
+                ],
+            )
+            .await?;
+        if answer.return_code != 0 {
+            self.logout();
+            return Err(XQueueError::Refused("login", answer.content));
+        }
+        Ok(())
+    }
+
+    /// Number of submissions waiting to be graded
+    pub async fn queue_len(&self) -> Result<usize, XQueueError> {
+        let answer = self.get("get_queuelen").await?;
+        match answer.content.as_u64() {
+            Some(len) if answer.return_code == 0 => Ok(usize::try_from(len).unwrap_or(usize::MAX)),
+            _ => Err(XQueueError::Refused("get_queuelen", answer.content)),
+        }
+    }
+
+    /// Take a submission out of the queue. Once its header has been decoded,
+    /// decoding errors are reported as [`XQueueError::Invalid`].
+    pub async fn get_submission(&self) -> Result<Submission, XQueueError> {
+        let answer = self.get("get_submission").await?;
+        let content = match answer.content {
+            serde_json::Value::String(ref content) if answer.return_code == 0 => content,
+            _ => return Err(XQueueError::Refused("get_submission", answer.content)),
+        };
+        log::debug!("received submission {content}");
+        let raw: RawSubmission = serde_json::from_str(content)?;
+        let header: Header = serde_json::from_str(&raw.xqueue_header)?;
+        match self.decode(&raw, header.submission_id) {
+            Ok((lab, zip_url)) => Ok(Submission {
+                header: raw.xqueue_header,
+                submission_id: header.submission_id,
+                lab,
+                zip_url,
+            }),
+            Err(e) => Err(XQueueError::Invalid(
+                raw.xqueue_header,
+                header.submission_id,
+                Box::new(e),
+            )),
+        }
+    }
+
+    /// Return the lab and the URL of the archive of a submission
+    fn decode(
+        &self,
+        raw: &RawSubmission,
+        submission_id: u64,
+    ) -> Result<(String, String), XQueueError> {
+        let body: Body = serde_json::from_str(&raw.xqueue_body).map_err(XQueueError::Content)?;
+        let payload: GraderPayload =
+            serde_json::from_str(&body.grader_payload).map_err(XQueueError::Content)?;
+        let mut files: HashMap<String, String> =
+            serde_json::from_str(&raw.xqueue_files).map_err(XQueueError::Content)?;
+        let zip_url = files
+            .remove(&self.config.zip_name)
+            .ok_or_else(|| XQueueError::MissingFile(self.config.zip_name.clone(), submission_id))?;
+        Ok((payload.lab, zip_url))
+    }
+
+    /// Post the grade of the submission identified by `header`
+    pub async fn put_result(&self, header: &str, grade: &Grade) -> Result<(), XQueueError> {
+        let body = serde_json::to_string(grade)?;
+        let answer = self
+            .post(
+                "put_result",
+                &[("xqueue_header", header), ("xqueue_body", &body)],
+            )
+            .await?;
+        if answer.return_code != 0 {
+            return Err(XQueueError::Refused("put_result", answer.content));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Method, Response};
+    use hyper_util::rt::TokioIo;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// Submission whose files are `files`
+    fn submission(id: u64, files: &str) -> serde_json::Value {
+        serde_json::json!({
+            "xqueue_header": format!(r#"{{"submission_id": {id}, "submission_key": "k"}}"#),
+            "xqueue_body": r#"{"student_info": "{}", "grader_payload": "{\"lab\": \"lab1\"}"}"#,
+            "xqueue_files": files,
+        })
+    }
+
+    /// Minimal XQueue holding `submissions`, which records the posted results
+    async fn mock_xqueue(
+        results: Arc<Mutex<Vec<(String, String)>>>,
+        submissions: Vec<serde_json::Value>,
+    ) -> Url {
+        let submissions = Arc::new(Mutex::new(submissions));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let results = results.clone();
+                let submissions = submissions.clone();
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let results = results.clone();
+                    let submissions = submissions.clone();
+                    async move {
+                        let logged_in = request
+                            .headers()
+                            .get(COOKIE)
+                            .is_some_and(|cookie| cookie == "sessionid=42");
+                        let method = request.method().clone();
+                        let path = request.uri().path().to_owned();
+                        let body = request.into_body().collect().await?.to_bytes();
+                        let params = form_urlencoded::parse(&body)
+                            .into_owned()
+                            .collect::<HashMap<_, _>>();
+                        let mut response = Response::builder();
+                        let answer = match (method, path.as_str()) {
+                            (Method::POST, "/xqueue/login/") => {
+                                if params["password"] == "secret" {
+                                    response = response.header(SET_COOKIE, "sessionid=42; Path=/");
+                                    serde_json::json!({"return_code": 0, "content": "Logged in"})
+                                } else {
+                                    serde_json::json!({"return_code": 1, "content": "Incorrect login credentials"})
+                                }
+                            }
+                            _ if !logged_in => {
+                                serde_json::json!({"return_code": 1, "content": "login_required"})
+                            }
+                            (Method::GET, "/xqueue/get_queuelen/") => {
+                                serde_json::json!({"return_code": 0, "content": submissions.lock().unwrap().len()})
+                            }
+                            (Method::GET, "/xqueue/get_submission/") => {
+                                let mut submissions = submissions.lock().unwrap();
+                                if submissions.is_empty() {
+                                    serde_json::json!({"return_code": 1, "content": "Queue is empty"})
+                                } else {
+                                    let content = submissions.remove(0);
+                                    serde_json::json!({"return_code": 0, "content": content.to_string()})
+                                }
+                            }
+                            (Method::POST, "/xqueue/put_result/") => {
+                                results.lock().unwrap().push((
+                                    params["xqueue_header"].clone(),
+                                    params["xqueue_body"].clone(),
+                                ));
+                                serde_json::json!({"return_code": 0, "content": ""})
+                            }
+                            _ => serde_json::json!({"return_code": 1, "content": "unknown"}),
+                        };
+                        Ok::<_, hyper::Error>(
+                            response
+                                .header(CONTENT_TYPE, "text/html")
+                                .body(Full::new(Bytes::from(answer.to_string())))
+                                .unwrap(),
+                        )
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        url
+    }
+
+    fn configuration(base_url: Url, password: &str) -> XQueueConfiguration {
+        XQueueConfiguration {
+            base_url,
+            username: String::from("grader"),
+            password: password.to_owned(),
+            queue_name: None,
+            amqp_queue: String::from("xqueue"),
+            poll_delay: 1,
+            zip_name: String::from("submission.zip"),
+            dir: String::from("dragon-tiger"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_xqueue() {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let base_url = mock_xqueue(
+            results.clone(),
+            vec![
+                submission(7, r#"{"submission.zip": "http://files/7.zip"}"#),
+                submission(8, r#"{"other.zip": "http://files/8.zip"}"#),
+            ],
+        )
+        .await;
+        let xqueue = XQueue::new(&configuration(base_url.clone(), "wrong"));
+        assert!(matches!(
+            xqueue.login().await,
+            Err(XQueueError::Refused("login", _))
+        ));
+        assert!(!xqueue.is_logged_in());
+        let xqueue = XQueue::new(&configuration(base_url, "secret"));
+        xqueue.login().await.unwrap();
+        assert_eq!(xqueue.queue_len().await.unwrap(), 2);
+        let submission = xqueue.get_submission().await.unwrap();
+        assert_eq!(
+            submission,
+            Submission {
+                header: String::from(r#"{"submission_id": 7, "submission_key": "k"}"#),
+                submission_id: 7,
+                lab: String::from("lab1"),
+                zip_url: String::from("http://files/7.zip"),
+            }
+        );
+        let grade = Grade {
+            correct: true,
+            score: 3,
+            msg: String::from("<p>ok</p>"),
+        };
+        xqueue.put_result(&submission.header, &grade).await.unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![(
+                submission.header,
+                String::from(r#"{"correct":true,"score":3,"msg":"<p>ok</p>"}"#)
+            )]
+        );
+
+        // A submission without archive is answered rather than lost
+        let Err(XQueueError::Invalid(header, 8, e)) = xqueue.get_submission().await else {
+            panic!("submission without archive accepted");
+        };
+        assert!(matches!(*e, XQueueError::MissingFile(_, 8)));
+        xqueue
+            .put_result(&header, &crate::report::invalid_submission(&e))
+            .await
+            .unwrap();
+        let (posted_header, body) = results.lock().unwrap().pop().unwrap();
+        assert_eq!(posted_header, header);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["score"], 0);
+        assert_eq!(body["correct"], false);
+        assert!(
+            body["msg"]
+                .as_str()
+                .unwrap()
+                .contains("no submission.zip file in submission 8")
+        );
+        assert_eq!(xqueue.queue_len().await.unwrap(), 0);
+        xqueue.logout();
+        assert!(matches!(
+            xqueue.queue_len().await,
+            Err(XQueueError::Refused("get_queuelen", _))
+        ));
+    }
+}